@@ -0,0 +1,258 @@
+use crate::cgroup::Cgroup;
+use crate::command::SESSION_VARIABLE;
+use crate::error::AppError;
+use crate::output::CommandOutput;
+use std::ffi::CString;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitStatus};
+
+// where the container of init.sh mounts the workspace through cairn-fuse
+const CONTAINER_CHROOT_DIR: &str = "/usr/src/fusemount";
+// the directory served by cairn-fuse inside the container, the host side of it is MNT_DIR
+const CONTAINER_ROOT_DIR: &str = "/usr/src/dockermount";
+// the host side of it is LOG_DIR
+const CONTAINER_LOG_DIR: &str = "/usr/src/cairnlog";
+
+// Runs the traced command somewhere cairn-fuse can see it
+pub trait ExecutionBackend: Debug {
+    // The directory served by cairn-fuse, every path in the trace starts with it
+    fn root(&self) -> &str;
+
+    // Runs the program in `argv[0]` with the rest of `argv` as its arguments, in `workdir`, which
+    // is relative to the root, and waits for it to exit. The command and everything it starts
+    // belong to `session`, if cairn-fuse is running one for it.
+    fn run(
+        &self,
+        workdir: &str,
+        argv: &[String],
+        session: Option<&str>,
+        output: &CommandOutput,
+    ) -> Result<Exit, AppError>;
+}
+
+// How the traced command ended
+#[derive(Debug)]
+pub struct Exit {
+    // the process whose operations are traced, only unknown if the command failed to start
+    pub pid: Option<u32>,
+    pub status: ExitStatus,
+}
+
+// A container set up by init.sh, reached through `docker exec` or `podman exec`
+#[derive(Debug)]
+pub struct Container {
+    engine: String,
+    name: String,
+    log_dir: PathBuf,
+}
+
+impl Container {
+    pub fn new(engine: &str, name: &str, log_dir: &str) -> Self {
+        Self {
+            engine: engine.to_string(),
+            name: name.to_string(),
+            log_dir: PathBuf::from(log_dir),
+        }
+    }
+}
+
+impl ExecutionBackend for Container {
+    fn root(&self) -> &str {
+        CONTAINER_ROOT_DIR
+    }
+
+    fn run(
+        &self,
+        workdir: &str,
+        argv: &[String],
+        session: Option<&str>,
+        output: &CommandOutput,
+    ) -> Result<Exit, AppError> {
+        // command_wrapper.sh writes the pid of the command into the log directory, which both
+        // sides can see, so the output of the command does not have to be parsed for it
+        let pid_file = format!("command-{}.pid", process::id());
+        let host_pid_file = self.log_dir.join(&pid_file);
+        let _ = fs::remove_file(&host_pid_file);
+
+        let mut command = process::Command::new(&self.engine);
+        command.arg("exec");
+        // command_wrapper.sh puts the command into the cgroup of the session
+        if let Some(session) = session {
+            command
+                .arg("-e")
+                .arg(format!("{}={}", SESSION_VARIABLE, session));
+        }
+        // command_wrapper.sh exits with the status of the command
+        let (_, status) = output.run(
+            command
+                .args([&self.name, "./command_wrapper.sh", "-p"])
+                .arg(format!("{}/{}", CONTAINER_LOG_DIR, pid_file))
+                .args([CONTAINER_CHROOT_DIR, workdir])
+                .args(argv),
+        )?;
+
+        // no pid file means that the command could not be started, which command_wrapper.sh
+        // reports with a status of its own
+        let pid = match fs::read_to_string(&host_pid_file) {
+            Ok(pid) => pid,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !status.success() => {
+                return Ok(Exit { pid: None, status })
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("{} was not written: {}", host_pid_file.display(), e),
+                )
+                .into())
+            }
+        };
+        fs::remove_file(&host_pid_file)?;
+
+        match pid.trim().parse::<u32>() {
+            Ok(pid) => Ok(Exit {
+                pid: Some(pid),
+                status,
+            }),
+            Err(_) => Err(AppError::Unknown),
+        }
+    }
+}
+
+// A cairn-fuse mount on this host, which the command is chrooted into. Needs root.
+#[derive(Debug)]
+pub struct Chroot {
+    root: String,
+    mount_point: String,
+}
+
+impl Chroot {
+    pub fn new(root: &str, mount_point: &str) -> io::Result<Self> {
+        Ok(Self {
+            root: fs::canonicalize(root)?.to_string_lossy().to_string(),
+            mount_point: mount_point.to_string(),
+        })
+    }
+}
+
+impl ExecutionBackend for Chroot {
+    fn root(&self) -> &str {
+        &self.root
+    }
+
+    fn run(
+        &self,
+        workdir: &str,
+        argv: &[String],
+        session: Option<&str>,
+        output: &CommandOutput,
+    ) -> Result<Exit, AppError> {
+        let mut command = chrooted(Path::new(&self.mount_point), workdir, argv)?;
+        let _cgroup = join_session(&mut command, session);
+        let (pid, status) = output.run(&mut command)?;
+
+        Ok(Exit {
+            pid: Some(pid),
+            status,
+        })
+    }
+}
+
+// No sandbox, the command runs in the workdir of a cairn-fuse mount on this host and only the
+// log is read. Whatever the command does outside of the mount is not traced.
+#[derive(Debug)]
+pub struct Host {
+    root: String,
+    mount_point: String,
+}
+
+impl Host {
+    pub fn new(root: &str, mount_point: &str) -> io::Result<Self> {
+        Ok(Self {
+            root: fs::canonicalize(root)?.to_string_lossy().to_string(),
+            mount_point: mount_point.to_string(),
+        })
+    }
+}
+
+impl ExecutionBackend for Host {
+    fn root(&self) -> &str {
+        &self.root
+    }
+
+    fn run(
+        &self,
+        workdir: &str,
+        argv: &[String],
+        session: Option<&str>,
+        output: &CommandOutput,
+    ) -> Result<Exit, AppError> {
+        let mut command = process::Command::new(&argv[0]);
+        command
+            .args(&argv[1..])
+            .current_dir(Path::new(&self.mount_point).join(workdir));
+        let _cgroup = join_session(&mut command, session);
+        let (pid, status) = output.run(&mut command)?;
+
+        Ok(Exit {
+            pid: Some(pid),
+            status,
+        })
+    }
+}
+
+// Binds `command` to `session`, through the variable that cairn-fuse follows from every process
+// to the ones it starts and through a cgroup of its own, which the command can not leave. The
+// cgroup is left out where it can not be created, it has to be kept until the command exited.
+pub fn join_session(command: &mut process::Command, session: Option<&str>) -> Option<Cgroup> {
+    let session = session?;
+    command.env(SESSION_VARIABLE, session);
+
+    match Cgroup::create(session) {
+        Ok(cgroup) => {
+            cgroup.enter(command);
+            Some(cgroup)
+        }
+        // without cgroup v2 or the permission to use it, only the variable is there. Containers
+        // usually mount the cgroups read-only.
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::Unsupported | io::ErrorKind::PermissionDenied
+            ) || e.raw_os_error() == Some(libc::EROFS) =>
+        {
+            None
+        }
+        Err(e) => {
+            eprintln!(
+                "No cgroup for the command, processes that leave its process tree are not traced: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+// Builds a command that runs `argv` in `workdir` inside `dir`, like command_wrapper.sh. The
+// program is looked up in PATH after the chroot.
+pub fn chrooted(dir: &Path, workdir: &str, argv: &[String]) -> io::Result<process::Command> {
+    let root = CString::new(dir.as_os_str().as_bytes())?;
+    let workdir = CString::new(Path::new("/").join(workdir).as_os_str().as_bytes())?;
+
+    let mut command = process::Command::new(&argv[0]);
+    command.args(&argv[1..]);
+    unsafe {
+        command.pre_exec(move || {
+            if libc::chroot(root.as_ptr()) != 0 || libc::chdir(workdir.as_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    Ok(command)
+}
@@ -0,0 +1,340 @@
+use crate::command::{self, MutCommand};
+use crate::error::AppError;
+use crate::summary::Summary;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Reads the commands of a build script, one per line. Empty lines and lines starting with `#` are
+// left out, a line ending with `\` goes on in the next one.
+pub fn read_script(path: &str) -> io::Result<Vec<String>> {
+    let mut commands = Vec::new();
+    let mut current = String::new();
+
+    // the empty line at the end ends a command that is continued on the last line
+    let script = fs::read_to_string(path)?;
+    for line in script.lines().chain([""]) {
+        match line.strip_suffix('\\') {
+            Some(continued) => {
+                current.push_str(continued);
+                continue;
+            }
+            None => current.push_str(line),
+        }
+
+        let command = current.trim();
+        if !command.is_empty() && !command.starts_with('#') {
+            commands.push(command.to_string());
+        }
+        current.clear();
+    }
+
+    Ok(commands)
+}
+
+// What the last successful run of every command of a build left behind, in the style of
+// Memoize and fabricate: the state of the paths it read or looked for, and the paths it wrote.
+// Paths are relative to the traced root, on the host it is MNT_DIR.
+pub struct BuildDatabase {
+    path: PathBuf,
+    mnt_dir: PathBuf,
+    records: Vec<Value>,
+}
+
+impl BuildDatabase {
+    // A missing database is an empty one, every command runs
+    pub fn open(path: &str, mnt_dir: &str) -> Result<Self, AppError> {
+        let records = match fs::read_to_string(path) {
+            Ok(content) => match serde_json::from_str(&content) {
+                Ok(Value::Array(records)) => records,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} is not a build database", path),
+                    )
+                    .into())
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            path: PathBuf::from(path),
+            mnt_dir: PathBuf::from(mnt_dir),
+            records,
+        })
+    }
+
+    // Whether the last run of `command` in `workdir` is still what it would do now: everything
+    // it read is unchanged, nothing it looked for showed up and everything it wrote is there
+    fn up_to_date(&self, workdir: &str, command: &str) -> io::Result<bool> {
+        let record = match self.position(workdir, command) {
+            Some(position) => &self.records[position],
+            None => return Ok(false),
+        };
+
+        for (path, recorded) in record["inputs"].as_object().into_iter().flatten() {
+            if fingerprint(&self.mnt_dir.join(path))? != *recorded {
+                return Ok(false);
+            }
+        }
+        for path in record["outputs"].as_array().into_iter().flatten() {
+            let path = path.as_str().unwrap_or_default();
+            if self.mnt_dir.join(path).symlink_metadata().is_err() {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Remembers what `command` did in `workdir` from `entries`, the trace of it
+    fn record(
+        &mut self,
+        workdir: &str,
+        command: &str,
+        entries: &[&command::LogEntry],
+        root: &str,
+    ) -> io::Result<()> {
+        let summary = Summary::new(entries);
+        let relative = |path: &str| {
+            Path::new(path)
+                .strip_prefix(root)
+                .ok()
+                .filter(|relative| !relative.as_os_str().is_empty())
+                .map(|relative| relative.to_string_lossy().to_string())
+        };
+
+        // a probe is an input that is not there
+        let mut inputs = Map::new();
+        for path in summary.inputs.iter().chain(&summary.probes) {
+            if let Some(path) = relative(path) {
+                let state = fingerprint(&self.mnt_dir.join(&path))?;
+                inputs.insert(path, state);
+            }
+        }
+        let outputs: Vec<String> = summary
+            .outputs
+            .iter()
+            .filter_map(|path| relative(path))
+            .collect();
+
+        self.forget(workdir, command);
+        self.records.push(json!({
+            "workdir": workdir,
+            "command": command,
+            "inputs": inputs,
+            "outputs": outputs,
+        }));
+        self.save()
+    }
+
+    fn forget(&mut self, workdir: &str, command: &str) {
+        if let Some(position) = self.position(workdir, command) {
+            self.records.remove(position);
+        }
+    }
+
+    fn position(&self, workdir: &str, command: &str) -> Option<usize> {
+        self.records
+            .iter()
+            .position(|record| record["workdir"] == workdir && record["command"] == command)
+    }
+
+    // Saved after every command, so that an interrupted build keeps what it did so far
+    fn save(&self) -> io::Result<()> {
+        let temporary = self.path.with_extension("tmp");
+        fs::write(&temporary, Value::Array(self.records.clone()).to_string())?;
+        fs::rename(temporary, &self.path)
+    }
+}
+
+// A command of a build script, which only runs if the database does not know it to be up to date
+pub struct BuildStep<'a> {
+    command: command::Command,
+    workdir: String,
+    // the line of the script, which the command is known by
+    line: String,
+    // the position of the command in the script and how many commands it has
+    position: (usize, usize),
+    database: &'a RefCell<BuildDatabase>,
+}
+
+impl<'a> BuildStep<'a> {
+    pub fn new(
+        command: command::Command,
+        workdir: &str,
+        line: &str,
+        position: (usize, usize),
+        database: &'a RefCell<BuildDatabase>,
+    ) -> Self {
+        Self {
+            command,
+            workdir: workdir.to_string(),
+            line: line.to_string(),
+            position,
+            database,
+        }
+    }
+}
+
+impl MutCommand for BuildStep<'_> {
+    fn execute(&mut self) -> Result<(), AppError> {
+        let (index, count) = self.position;
+        if self
+            .database
+            .borrow()
+            .up_to_date(&self.workdir, &self.line)?
+        {
+            eprintln!("[{}/{}] {} (up to date)", index, count, self.line);
+            return Ok(());
+        }
+        eprintln!("[{}/{}] {}", index, count, self.line);
+
+        // a command that fails has to run again, whatever it did before
+        let mut database = self.database.borrow_mut();
+        database.forget(&self.workdir, &self.line);
+        database.save()?;
+        drop(database);
+
+        self.command.execute()?;
+
+        let entries: Vec<&command::LogEntry> = self.command.entries().iter().collect();
+        self.database.borrow_mut().record(
+            &self.workdir,
+            &self.line,
+            &entries,
+            self.command.root(),
+        )?;
+        Ok(())
+    }
+}
+
+// The state of `path` that a command can depend on: the hash of the contents of a file or of the
+// names in a directory, or null if there is nothing at the path
+fn fingerprint(path: &Path) -> io::Result<Value> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Value::Null),
+        // a file where a directory on the way would be
+        Err(e) if e.raw_os_error() == Some(libc::ENOTDIR) => return Ok(Value::Null),
+        Err(e) => return Err(e),
+    };
+
+    let mut hasher = Sha256::new();
+    if metadata.is_dir() {
+        let mut names = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        names.sort();
+        hasher.update(b"dir\0");
+        for name in names {
+            hasher.update(name.to_string_lossy().as_bytes());
+            hasher.update(b"\0");
+        }
+    } else {
+        hasher.update(fs::read(path)?);
+    }
+
+    let hash: String = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok(Value::String(hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::LogEntry;
+    use tempfile::TempDir;
+
+    fn script(content: &str) -> Vec<String> {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("build.sh");
+        fs::write(&path, content).unwrap();
+        read_script(path.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn scripts_have_a_command_per_line() {
+        assert_eq!(
+            script("cc -c a.c\n\n# link\n  cc -o a a.o  \n"),
+            ["cc -c a.c", "cc -o a a.o"]
+        );
+        assert_eq!(
+            script("cc -c \\\n  a.c\nld a.o\n"),
+            ["cc -c   a.c", "ld a.o"]
+        );
+        // the last line continues into the end of the script
+        assert_eq!(script("ls\ntouch a \\"), ["ls", "touch a"]);
+    }
+
+    #[test]
+    fn fingerprints_follow_the_contents() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("a");
+        fs::write(&file, "a").unwrap();
+
+        let before = fingerprint(&file).unwrap();
+        assert!(before.is_string());
+        fs::write(&file, "b").unwrap();
+        assert_ne!(fingerprint(&file).unwrap(), before);
+        fs::write(&file, "a").unwrap();
+        assert_eq!(fingerprint(&file).unwrap(), before);
+
+        // a directory changes with the names in it, not with their contents
+        let listing = fingerprint(dir.path()).unwrap();
+        fs::write(&file, "b").unwrap();
+        assert_eq!(fingerprint(dir.path()).unwrap(), listing);
+        fs::write(dir.path().join("b"), "").unwrap();
+        assert_ne!(fingerprint(dir.path()).unwrap(), listing);
+
+        assert_eq!(fingerprint(&dir.path().join("c")).unwrap(), Value::Null);
+        assert_eq!(fingerprint(&file.join("c")).unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn commands_are_up_to_date_until_what_they_depend_on_changes() {
+        let logs = TempDir::new().unwrap();
+        let database_path = logs.path().join("build.json");
+        let database_path = database_path.to_str().unwrap();
+        let mnt = TempDir::new().unwrap();
+        let root = mnt.path().to_str().unwrap();
+        let mut database = BuildDatabase::open(database_path, root).unwrap();
+        assert!(!database.up_to_date("/", "cc").unwrap());
+
+        fs::write(mnt.path().join("a.c"), "int a;").unwrap();
+        fs::write(mnt.path().join("a.o"), "").unwrap();
+        let entries = [
+            LogEntry::new('r', &format!("{}/a.c", root)),
+            LogEntry::new('n', &format!("{}/a.h", root)),
+            LogEntry::new('w', &format!("{}/a.o", root)),
+        ];
+        let entries: Vec<&LogEntry> = entries.iter().collect();
+        database.record("/", "cc", &entries, root).unwrap();
+        assert!(database.up_to_date("/", "cc").unwrap());
+        assert!(!database.up_to_date("/src", "cc").unwrap());
+
+        fs::write(mnt.path().join("a.c"), "int b;").unwrap();
+        assert!(!database.up_to_date("/", "cc").unwrap());
+        fs::write(mnt.path().join("a.c"), "int a;").unwrap();
+        assert!(database.up_to_date("/", "cc").unwrap());
+
+        fs::write(mnt.path().join("a.h"), "").unwrap();
+        assert!(!database.up_to_date("/", "cc").unwrap());
+        fs::remove_file(mnt.path().join("a.h")).unwrap();
+
+        // the database is saved with every command
+        let database = BuildDatabase::open(database_path, root).unwrap();
+        assert!(database.up_to_date("/", "cc").unwrap());
+
+        fs::remove_file(mnt.path().join("a.o")).unwrap();
+        assert!(!database.up_to_date("/", "cc").unwrap());
+    }
+}
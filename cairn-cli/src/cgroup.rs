@@ -0,0 +1,112 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+// where the hierarchy of cgroup v2 is mounted
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+// cairn-fuse binds every process in a cgroup named `cairn-<session>` to that session
+const CGROUP_PREFIX: &str = "cairn-";
+
+// A cgroup of cgroup v2 for the command of a session, below the one of cairn-cli. A process can
+// not leave its cgroup by forking, so cairn-fuse finds the daemons of the command through it as
+// well. The cgroup is removed when it is dropped, unless something of the command is still in it.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    procs: File,
+}
+
+impl Cgroup {
+    // Needs cgroup v2 and write access to the cgroup of cairn-cli, which root and delegated
+    // cgroups of systemd have
+    pub fn create(session: &str) -> io::Result<Cgroup> {
+        let unsupported = || io::Error::new(io::ErrorKind::Unsupported, "cgroup v2 is not used");
+        // with cgroup v1 next to it, cgroup v2 is mounted somewhere else, if at all
+        if !Path::new(CGROUP_ROOT).join("cgroup.procs").exists() {
+            return Err(unsupported());
+        }
+        let cgroups = fs::read_to_string("/proc/self/cgroup")?;
+        let path = session_cgroup(&cgroups, session).ok_or_else(unsupported)?;
+        fs::create_dir(&path)?;
+        // opened before the command is started, a chroot would hide the file from it
+        let procs = match OpenOptions::new()
+            .write(true)
+            .open(path.join("cgroup.procs"))
+        {
+            Ok(procs) => procs,
+            Err(e) => {
+                let _ = fs::remove_dir(&path);
+                return Err(e);
+            }
+        };
+
+        Ok(Cgroup { path, procs })
+    }
+
+    // Moves the process of `command` into the cgroup right before it runs the program, so that
+    // everything it starts is in the cgroup from the start
+    pub fn enter(&self, command: &mut process::Command) {
+        let procs = self.procs.as_raw_fd();
+        unsafe {
+            command.pre_exec(move || {
+                // 0 is the process that writes it
+                if libc::write(procs, b"0".as_ptr().cast(), 1) != 1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+// The cgroup of `session` below the own one, which `cgroups`, the content of /proc/self/cgroup,
+// tells. Only cgroup v2 has the line starting with `0::`.
+fn session_cgroup(cgroups: &str, session: &str) -> Option<PathBuf> {
+    let own = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+
+    Some(
+        PathBuf::from(CGROUP_ROOT)
+            .join(own.trim_start_matches('/'))
+            .join(format!("{}{}", CGROUP_PREFIX, session)),
+    )
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // a cgroup can only be removed once it is empty, left over processes keep it and stay in
+        // the session until they exit
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_get_a_cgroup_below_the_own_one() {
+        assert_eq!(
+            session_cgroup("0::/user.slice/session-2.scope\n", "build-1"),
+            Some(PathBuf::from(
+                "/sys/fs/cgroup/user.slice/session-2.scope/cairn-build-1"
+            ))
+        );
+        // the root cgroup, in a container with a cgroup namespace of its own
+        assert_eq!(
+            session_cgroup("0::/\n", "build-1"),
+            Some(PathBuf::from("/sys/fs/cgroup/cairn-build-1"))
+        );
+        // the cgroup v2 line of a hybrid setup comes after the ones of cgroup v1
+        assert_eq!(
+            session_cgroup(
+                "2:cpu,cpuacct:/user.slice\n1:name=systemd:/init.scope\n0::/init.scope\n",
+                "1"
+            ),
+            Some(PathBuf::from("/sys/fs/cgroup/init.scope/cairn-1"))
+        );
+        assert_eq!(session_cgroup("1:name=systemd:/init.scope\n", "1"), None);
+    }
+}
@@ -1,10 +1,24 @@
+use crate::backend::ExecutionBackend;
 use crate::error::AppError;
-use crate::util::stream_output;
+use crate::filter::Filter;
+use crate::format::Format;
+use crate::output::CommandOutput;
+use crate::snapshot::{self, Snapshot};
+use crate::tree::ProcessTree;
 use regex::Regex;
-use std::collections::{HashSet, VecDeque};
-use std::fs::File;
-use std::io::Write;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// cairn-fuse binds the processes started with this variable to the session it names, the
+// backends set it for the command
+pub const SESSION_VARIABLE: &str = "CAIRN_SESSION";
+
+// operations that change the path they are applied to
+const CHANGING_OPS: [char; 5] = ['w', 'c', 't', 'd', 'm'];
 
 pub trait MutCommand {
     fn execute(&mut self) -> Result<(), AppError>;
@@ -12,144 +26,299 @@ pub trait MutCommand {
 
 #[derive(Debug)]
 pub struct Command {
-    executable: String,
-    args: Vec<String>,
+    backend: Box<dyn ExecutionBackend>,
+    workdir: String,
+    argv: Vec<String>,
+    command_output: CommandOutput,
+    filter: Filter,
+    format: Format,
     output_path: String,
-    root_ppid: Option<u32>,
-    start_time: u32,
+    // whether the trace is added to the output instead of replacing it
+    append_output: bool,
+    // the length of the trace before the command started, its entries come after it
+    log_offset: u64,
+    // the session of cairn-fuse that the command runs in, if cairn-fuse supports them
+    session: Option<String>,
+    // the trace of the command, once it ran
+    entries: Vec<LogEntry>,
 }
 
-#[derive(Clone)]
-struct LogEntry {
-    timestamp: u32,
-    pid: u32,
-    ppid: u32,
-    op: char,
-    path: String,
+#[derive(Clone, Debug)]
+pub struct LogEntry {
+    pub timestamp: u32,
+    pub pid: u32,
+    pub ppid: i32,
+    pub op: char,
+    // every path of the operation, separated by `|`
+    pub path: String,
     order: u32,
 }
 
+impl LogEntry {
+    // The paths of the operation, without the name of it that debug builds of cairn-fuse add
+    pub fn paths(&self) -> Vec<&str> {
+        self.path
+            .split('|')
+            .filter(|path| path.starts_with('/'))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl LogEntry {
+    // An entry of a process without a parent, the tests set what else they need
+    pub fn new(op: char, path: &str) -> Self {
+        Self {
+            timestamp: 0,
+            pid: 1,
+            ppid: -1,
+            op,
+            path: path.to_string(),
+            order: 0,
+        }
+    }
+}
+
 impl Command {
-    pub fn new(executable: &str, args: Vec<&str>, output_path: &str) -> Self {
+    pub fn new(
+        backend: Box<dyn ExecutionBackend>,
+        workdir: &str,
+        argv: Vec<String>,
+        command_output: CommandOutput,
+        filter: Filter,
+        format: Format,
+        output_path: &str,
+    ) -> Self {
         Self {
-            executable: executable.to_string(),
-            args: args.iter().map(|s| s.to_string()).collect(),
+            backend,
+            workdir: workdir.to_string(),
+            argv,
+            command_output,
+            filter,
+            format,
             output_path: output_path.to_string(),
-            root_ppid: None,
-            start_time: 0,
+            append_output: false,
+            log_offset: 0,
+            session: None,
+            entries: Vec::new(),
         }
     }
 
-    fn process_log(&self) -> Result<(), AppError> {
-        let mnt_dir = std::env::var("MNT_DIR").expect("ERROR: MNT_DIR not set");
-        let log_file =
-            File::open(format!("{}/tracer.log", mnt_dir)).expect("ERROR: Could not open log file");
+    // Adds the trace to the output file instead of replacing it, for commands that share one
+    pub fn append_output(mut self) -> Self {
+        self.append_output = true;
+        self
+    }
+
+    // The directory served by cairn-fuse, every path of the trace starts with it
+    pub fn root(&self) -> &str {
+        self.backend.root()
+    }
 
-        let res = self.parse_lines(
-            BufReader::new(log_file)
-                .lines()
-                .map(|l| l.unwrap())
-                .collect::<Vec<String>>(),
-        );
+    // The entries of the command, in the order they were traced, once it ran
+    pub fn entries(&self) -> &[LogEntry] {
+        &self.entries
+    }
+
+    // `root` is the process of the command, the trace is narrowed down to the ones below it
+    fn process_log(&self, log_dir: &str, root: u32) -> Result<Vec<LogEntry>, AppError> {
+        let log_path = match &self.session {
+            Some(session) => session_log(log_dir, session),
+            None => format!("{}/tracer.log", log_dir),
+        };
+        // processes that outlive the command can still be adding to the trace, both passes have
+        // to stop at the same place
+        let log_end = fs::metadata(&log_path)?.len();
 
-        let root_ppid = self.root_ppid.unwrap();
-        let mut ppids = HashSet::new();
-        ppids.insert(root_ppid);
+        // the first pass only keeps the parents, the entries of the command are not known
+        // before its whole process tree is
+        let mut tree = ProcessTree::new(root);
+        self.read_log(&log_path, log_end, |entry| tree.add(&entry))?;
+        let pids = tree.descendants();
 
+        // cairn-fuse already decided which processes belong to a session, that includes the
+        // ones that left the process tree of the command
         let mut filtered_results: Vec<LogEntry> = Vec::new();
-        let mut queue = VecDeque::from(res);
-        let mut queue_size = queue.len();
-
-        while queue_size == queue.len() {
-            queue_size = queue.len();
-            let mut backup_queue = VecDeque::new();
-
-            while let Some(result) = queue.pop_front() {
-                if result.timestamp < self.start_time {
-                    continue;
-                }
-
-                if result.pid == root_ppid {
-                    filtered_results.push(result.clone());
-                } else if ppids.contains(&result.ppid) {
-                    filtered_results.push(result.clone());
-                    ppids.insert(result.pid);
-                } else {
-                    backup_queue.push_back(result);
-                }
+        self.read_log(&log_path, log_end, |entry| {
+            if self.session.is_some() || pids.contains(&entry.pid) {
+                filtered_results.push(entry);
             }
+        })?;
 
-            queue = backup_queue;
+        filtered_results.sort_by(|a, b| a.order.cmp(&b.order));
+        let mut output: Box<dyn Write> = match self.output_path.as_str() {
+            "-" => Box::new(io::stdout().lock()),
+            path => Box::new(
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .append(self.append_output)
+                    .truncate(!self.append_output)
+                    .open(path)?,
+            ),
+        };
+        // the filter only narrows down the output, the snapshot needs every entry
+        let shown: Vec<&LogEntry> = filtered_results
+            .iter()
+            .filter(|result| {
+                self.filter
+                    .matches(result.op, &result.paths(), self.backend.root())
+            })
+            .collect();
+        match self.format {
+            Format::Tree => tree.write(&mut output, &shown)?,
+            format => format.write(&mut output, &shown, self.backend.root(), &self.workdir)?,
         }
+        output.flush()?;
 
-        filtered_results.sort_by(|a, b| a.order.cmp(&b.order));
-        let mut file = File::create(format!("{}", self.output_path))?;
-        for result in filtered_results {
-            writeln!(&mut file, "{}|{}", result.op, result.path)?;
+        Ok(filtered_results)
+    }
+
+    // Keeps the original state of every path that the command changed
+    fn take_snapshot(&self, log_dir: &str, entries: &[LogEntry]) -> Result<(), AppError> {
+        let paths = entries
+            .iter()
+            .filter(|entry| CHANGING_OPS.contains(&entry.op))
+            // a rename has both paths in the entry, debug builds add the name of the operation
+            .flat_map(|entry| entry.path.split('|'))
+            .filter_map(|path| Path::new(path).strip_prefix(self.backend.root()).ok())
+            .filter(|path| !path.as_os_str().is_empty())
+            .map(PathBuf::from)
+            .collect();
+
+        let latest = format!("{}/snapshot", log_dir);
+        match &self.session {
+            // sessions that run at the same time keep their snapshots apart, a rollback restores
+            // the one of the command that finished last
+            Some(session) => {
+                let snapshot = Snapshot::new(&format!("{}/snapshots/{}", log_dir, session));
+                snapshot.take(Path::new(&session_backup(log_dir, session)), paths)?;
+                snapshot.make_latest(Path::new(&latest))
+            }
+            None => Snapshot::new(&latest).take(Path::new(&format!("{}/backup", log_dir)), paths),
         }
+    }
+
+    // Runs the command and processes its trace, a failed command is traced all the same, what it
+    // changed can still be rolled back
+    fn run(&mut self, log_dir: &str) -> Result<(), AppError> {
+        let exit = self.backend.run(
+            &self.workdir,
+            &self.argv,
+            self.session.as_deref(),
+            &self.command_output,
+        )?;
+        // a command that could not be started did not do anything to trace
+        let root = match exit.pid {
+            Some(pid) => pid,
+            None if !exit.status.success() => return Err(exit.status.into()),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "the process of the command is not known",
+                )
+                .into())
+            }
+        };
+
+        let entries = self.process_log(log_dir, root)?;
+        self.take_snapshot(log_dir, &entries)?;
+        self.entries = entries;
 
+        if !exit.status.success() {
+            return Err(exit.status.into());
+        }
         Ok(())
     }
 
-    fn parse_lines(&self, lines: Vec<String>) -> Vec<LogEntry> {
-        let regex_str = r"^\[INFO\] -> (\d+): (\d+)\|(\d+)\|([a-z])\|(.*)$";
+    // Streams the entries of the trace between the offset it had before the command started and
+    // `end` to `visit`, in the order they were traced
+    fn read_log(
+        &self,
+        log_path: &str,
+        end: u64,
+        mut visit: impl FnMut(LogEntry),
+    ) -> Result<(), AppError> {
+        // timestamp: tgid|tid|ppid|uid|gid|op|path
+        let regex_str = r"^(\d+): (\d+)\|\d+\|(-?\d+)\|\d+\|\d+\|([a-z])\|(.*)$";
         let regex = Regex::new(regex_str).unwrap();
 
+        let mut log_file = File::open(log_path).expect("ERROR: Could not open log file");
+        log_file.seek(SeekFrom::Start(self.log_offset))?;
+        let reader = BufReader::new(log_file.take(end.saturating_sub(self.log_offset)));
+
         let mut order = 0;
+        for line in reader.lines() {
+            let line = line?;
+            if let Some(captures) = regex.captures(line.as_str()) {
+                let timestamp = captures.get(1).unwrap().as_str().parse::<u32>().unwrap();
+                let pid = captures.get(2).unwrap().as_str().parse::<u32>().unwrap();
+                let ppid = captures.get(3).unwrap().as_str().parse::<i32>().unwrap();
+                let op = captures.get(4).unwrap().as_str().chars().next().unwrap();
+                let path = captures.get(5).unwrap().as_str().to_string();
 
-        lines
-            .into_iter()
-            .map(|line| {
-                if let Some(captures) = regex.captures(line.as_str()) {
-                    let timestamp = captures.get(1).unwrap().as_str().parse::<u32>().unwrap();
-                    let pid = captures.get(2).unwrap().as_str().parse::<u32>().unwrap();
-                    let ppid = captures.get(3).unwrap().as_str().parse::<u32>().unwrap();
-                    let op = captures.get(4).unwrap().as_str().chars().next().unwrap();
-                    let path = captures.get(5).unwrap().as_str().to_string();
-
-                    order += 1;
-
-                    Some(LogEntry {
-                        timestamp,
-                        pid,
-                        ppid,
-                        op,
-                        path,
-                        order,
-                    })
-                } else {
-                    None
-                }
-            })
-            .filter(|l| l.is_some())
-            .map(|l| l.unwrap())
-            .collect()
+                order += 1;
+
+                visit(LogEntry {
+                    timestamp,
+                    pid,
+                    ppid,
+                    op,
+                    path,
+                    order,
+                });
+            }
+        }
+
+        Ok(())
     }
 }
 
+fn session_log(log_dir: &str, session: &str) -> String {
+    format!("{}/sessions/{}.log", log_dir, session)
+}
+
+// cairn-fuse keeps the pre-images of every session apart
+fn session_backup(log_dir: &str, session: &str) -> String {
+    format!("{}/backup/{}", log_dir, session)
+}
+
 impl MutCommand for Command {
     fn execute(&mut self) -> Result<(), AppError> {
-        self.start_time = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_secs() as u32;
-
-        let mut child = std::process::Command::new(&self.executable)
-            .args(&self.args)
-            .stdout(std::process::Stdio::piped())
-            .spawn()?;
-
-        let output = stream_output(&mut child)?;
-
-        if let Some(last_line) = output.lines().collect::<Vec<&str>>().last() {
-            if let Ok(pid) = last_line.parse::<u32>() {
-                self.root_ppid = Some(pid);
-            } else {
-                return Err(AppError::Unknown);
-            }
+        let log_dir = std::env::var("LOG_DIR").expect("ERROR: LOG_DIR not set");
+
+        // with sessions, the command gets an event stream and a backup of its own, otherwise the
+        // trace and the backup are shared by every command and only what is added to them from
+        // here on belongs to it
+        if Path::new(&format!("{}/sessions", log_dir)).is_dir() {
+            let session = format!(
+                "{}-{}",
+                process::id(),
+                SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos()
+            );
+            File::create(session_log(&log_dir, &session))?;
+            self.session = Some(session);
+        } else {
+            snapshot::clear_backup(Path::new(&format!("{}/backup", log_dir)))?;
+            self.log_offset = match fs::metadata(format!("{}/tracer.log", log_dir)) {
+                Ok(metadata) => metadata.len(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e.into()),
+            };
         }
 
-        self.process_log()?;
+        let result = self.run(&log_dir);
 
-        Ok(())
+        // the session ends whatever happened to the command, the first error is the one that
+        // is reported
+        let ended = match &self.session {
+            Some(session) => fs::remove_file(session_log(&log_dir, session))
+                .map_err(AppError::from)
+                .and_then(|_| {
+                    snapshot::remove_backup(Path::new(&session_backup(&log_dir, session)))
+                }),
+            None => Ok(()),
+        };
+        result.and(ended)
     }
 }
@@ -0,0 +1,119 @@
+use glob::{MatchOptions, Pattern};
+use std::path::Path;
+
+// every operation that cairn-fuse traces
+pub const OPS: &str = "rwmdqtcn";
+// the operations that are dumped unless others are asked for, the lookups that found nothing are
+// many and only of use to build systems
+pub const DEFAULT_OPS: &str = "rwmdqtc";
+
+// `*` stays within a directory, `**` goes through any number of them
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+// Decides which entries of the trace end up in the output
+#[derive(Clone, Debug)]
+pub struct Filter {
+    ops: String,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl Filter {
+    // `ops` are the characters of the operations to keep. An entry is kept if one of its paths
+    // matches any of the `include` globs, or there are none, and none of the `exclude` globs.
+    pub fn new(ops: &str, include: Vec<Pattern>, exclude: Vec<Pattern>) -> Self {
+        Self {
+            ops: ops.to_string(),
+            include,
+            exclude,
+        }
+    }
+
+    // The globs are matched against the paths as the command saw them, without `root`, the
+    // directory served by cairn-fuse
+    pub fn matches(&self, op: char, paths: &[&str], root: &str) -> bool {
+        self.ops.contains(op)
+            && paths.iter().any(|path| {
+                let path = visible_path(path, root);
+                self.includes(&path) && !self.excludes(&path)
+            })
+    }
+
+    fn includes(&self, path: &str) -> bool {
+        self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|glob| glob.matches_with(path, MATCH_OPTIONS))
+    }
+
+    fn excludes(&self, path: &str) -> bool {
+        self.exclude
+            .iter()
+            .any(|glob| glob.matches_with(path, MATCH_OPTIONS))
+    }
+}
+
+// Paths outside of `root` are left as they are
+fn visible_path(path: &str, root: &str) -> String {
+    match Path::new(path).strip_prefix(root) {
+        Ok(inside) => Path::new("/").join(inside).display().to_string(),
+        Err(_) => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: &str = "/tmp/root";
+
+    fn globs(globs: &[&str]) -> Vec<Pattern> {
+        globs
+            .iter()
+            .map(|glob| Pattern::new(glob).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn only_the_given_operations_are_kept() {
+        let filter = Filter::new("rw", Vec::new(), Vec::new());
+
+        assert!(filter.matches('r', &["/tmp/root/a"], ROOT));
+        assert!(filter.matches('w', &["/tmp/root/a"], ROOT));
+        assert!(!filter.matches('d', &["/tmp/root/a"], ROOT));
+    }
+
+    #[test]
+    fn globs_match_the_paths_the_command_sees() {
+        let filter = Filter::new(OPS, Vec::new(), globs(&["/usr/**"]));
+
+        assert!(!filter.matches('r', &["/tmp/root/usr/lib/libc.so"], ROOT));
+        assert!(filter.matches('r', &["/tmp/root/home/a.c"], ROOT));
+        // the root itself is `/`
+        assert!(filter.matches('r', &["/tmp/root"], ROOT));
+    }
+
+    #[test]
+    fn included_paths_are_kept_unless_excluded() {
+        let filter = Filter::new(OPS, globs(&["/src/**"]), globs(&["/src/*.o"]));
+
+        assert!(filter.matches('r', &["/tmp/root/src/a.c"], ROOT));
+        assert!(filter.matches('r', &["/tmp/root/src/lib/a.o"], ROOT));
+        assert!(!filter.matches('w', &["/tmp/root/src/a.o"], ROOT));
+        assert!(!filter.matches('r', &["/tmp/root/include/a.h"], ROOT));
+    }
+
+    #[test]
+    fn one_matching_path_keeps_the_entry() {
+        let filter = Filter::new(OPS, Vec::new(), globs(&["/tmp/**"]));
+
+        // a rename out of an excluded directory
+        assert!(filter.matches('m', &["/tmp/root/tmp/a", "/tmp/root/a"], ROOT));
+        assert!(!filter.matches('m', &["/tmp/root/tmp/a", "/tmp/root/tmp/b"], ROOT));
+    }
+}
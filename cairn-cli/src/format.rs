@@ -0,0 +1,235 @@
+use crate::command::LogEntry;
+use crate::summary::Summary;
+use serde_json::json;
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::path::Path;
+
+pub const FORMATS: [&str; 6] = ["text", "json", "csv", "make", "ninja", "summary"];
+
+// How the trace of the command is written out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    // `op|paths` lines, the same as the trace of cairn-fuse without the process details
+    Text,
+    // a JSON object per line for every entry
+    Json,
+    Csv,
+    // a depfile that lists the files the command wrote as targets and the ones it read as their
+    // prerequisites, with an empty rule for every prerequisite so that make does not fail once
+    // one of them is deleted
+    Make,
+    // the same depfile without the empty rules, which ninja does not need
+    Ninja,
+    // the inputs, outputs, temporaries, probes and deleted paths of the command
+    Summary,
+    // the processes of the command with the operations of each of them, which needs the
+    // process tree, see ProcessTree
+    Tree,
+}
+
+impl Format {
+    // `name` is one of FORMATS
+    pub fn from_name(name: &str) -> Format {
+        match name {
+            "json" => Format::Json,
+            "csv" => Format::Csv,
+            "make" => Format::Make,
+            "ninja" => Format::Ninja,
+            "summary" => Format::Summary,
+            _ => Format::Text,
+        }
+    }
+
+    // Writes `entries`, which are in the order they were traced. `root` is the directory served
+    // by cairn-fuse and `workdir` is where the command ran, relative to it, depfiles and the
+    // summary use paths relative to the workdir like build systems do.
+    pub fn write(
+        &self,
+        out: &mut dyn Write,
+        entries: &[&LogEntry],
+        root: &str,
+        workdir: &str,
+    ) -> io::Result<()> {
+        match self {
+            Format::Text => {
+                for entry in entries {
+                    writeln!(out, "{}|{}", entry.op, entry.path)?;
+                }
+                Ok(())
+            }
+            Format::Json => {
+                for entry in entries {
+                    let event = json!({
+                        "timestamp": entry.timestamp,
+                        "pid": entry.pid,
+                        "ppid": entry.ppid,
+                        "op": entry.op.to_string(),
+                        "paths": entry.paths(),
+                    });
+                    writeln!(out, "{}", event)?;
+                }
+                Ok(())
+            }
+            Format::Csv => {
+                writeln!(out, "timestamp,pid,ppid,op,path,destination")?;
+                for entry in entries {
+                    let paths = entry.paths();
+                    writeln!(
+                        out,
+                        "{},{},{},{},{},{}",
+                        entry.timestamp,
+                        entry.pid,
+                        entry.ppid,
+                        entry.op,
+                        csv_field(paths.first().unwrap_or(&"")),
+                        csv_field(paths.get(1).unwrap_or(&""))
+                    )?;
+                }
+                Ok(())
+            }
+            Format::Make | Format::Ninja => {
+                let summary = Summary::new(entries);
+                let targets = relative_paths(&summary.outputs, root, workdir);
+                let prerequisites = relative_paths(&summary.inputs, root, workdir);
+
+                if targets.is_empty() {
+                    return Ok(());
+                }
+
+                write!(out, "{}:", escape_all(&targets))?;
+                for prerequisite in &prerequisites {
+                    write!(out, " \\\n  {}", depfile_escape(prerequisite))?;
+                }
+                writeln!(out)?;
+
+                if *self == Format::Make {
+                    for prerequisite in &prerequisites {
+                        writeln!(out, "\n{}:", depfile_escape(prerequisite))?;
+                    }
+                }
+                Ok(())
+            }
+            Format::Tree => Err(io::Error::from(io::ErrorKind::Unsupported)),
+            Format::Summary => {
+                let summary = Summary::new(entries);
+                let sections = [
+                    ("inputs", &summary.inputs),
+                    ("outputs", &summary.outputs),
+                    ("temporaries", &summary.temporaries),
+                    ("probes", &summary.probes),
+                    ("deleted", &summary.deleted),
+                ];
+
+                for (name, paths) in sections {
+                    writeln!(out, "{} ({}):", name, paths.len())?;
+                    for path in relative_paths(paths, root, workdir) {
+                        writeln!(out, "  {}", path)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Paths inside the workdir are made relative to it, the others are given as the command saw them
+fn relative_path(path: &str, root: &str, workdir: &str) -> String {
+    let inside = match Path::new(path).strip_prefix(root) {
+        Ok(inside) => inside,
+        Err(_) => return path.to_string(),
+    };
+
+    match inside.strip_prefix(workdir) {
+        Ok(relative) if !relative.as_os_str().is_empty() => relative.display().to_string(),
+        _ => Path::new("/").join(inside).display().to_string(),
+    }
+}
+
+fn relative_paths(paths: &BTreeSet<&str>, root: &str, workdir: &str) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| relative_path(path, root, workdir))
+        .collect()
+}
+
+fn escape_all(paths: &[String]) -> String {
+    paths
+        .iter()
+        .map(|path| depfile_escape(path))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Both make and ninja split on spaces, treat `#` as a comment and `$` as a variable
+fn depfile_escape(path: &str) -> String {
+    path.replace('$', "$$")
+        .replace(' ', "\\ ")
+        .replace('#', "\\#")
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: &str = "/tmp/root";
+
+    fn write(format: Format, trace: &[(char, &str)]) -> String {
+        let entries: Vec<LogEntry> = trace
+            .iter()
+            .map(|(op, path)| LogEntry::new(*op, path))
+            .collect();
+        let entries: Vec<&LogEntry> = entries.iter().collect();
+
+        let mut out = Vec::new();
+        format.write(&mut out, &entries, ROOT, "src").unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        assert_eq!(csv_field("/a/b.c"), "/a/b.c");
+        assert_eq!(csv_field("/a,b"), "\"/a,b\"");
+        assert_eq!(csv_field("/a\"b"), "\"/a\"\"b\"");
+        assert_eq!(csv_field("/a\nb"), "\"/a\nb\"");
+    }
+
+    #[test]
+    fn depfile_paths_are_escaped() {
+        assert_eq!(depfile_escape("a b.c"), "a\\ b.c");
+        assert_eq!(depfile_escape("#a.c"), "\\#a.c");
+        assert_eq!(depfile_escape("$a.c"), "$$a.c");
+        assert_eq!(escape_all(&["a b".into(), "c".into()]), "a\\ b c");
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_workdir() {
+        assert_eq!(relative_path("/tmp/root/src/a.c", ROOT, "src"), "a.c");
+        assert_eq!(
+            relative_path("/tmp/root/src/lib/a.c", ROOT, "src"),
+            "lib/a.c"
+        );
+        // outside of the workdir, as the command saw them
+        assert_eq!(relative_path("/tmp/root/src", ROOT, "src"), "/src");
+        assert_eq!(relative_path("/tmp/root/usr/a.h", ROOT, "src"), "/usr/a.h");
+        assert_eq!(relative_path("/proc/self", ROOT, "src"), "/proc/self");
+    }
+
+    #[test]
+    fn only_make_gets_empty_rules() {
+        let trace = [('r', "/tmp/root/src/a.c"), ('w', "/tmp/root/src/a.o")];
+
+        assert_eq!(write(Format::Ninja, &trace), "a.o: \\\n  a.c\n");
+        assert_eq!(write(Format::Make, &trace), "a.o: \\\n  a.c\n\na.c:\n");
+        // nothing written, nothing to depend on
+        assert_eq!(write(Format::Make, &trace[..1]), "");
+    }
+}
@@ -1,80 +1,286 @@
 mod app;
+mod backend;
+mod build;
+mod cgroup;
 mod command;
 mod error;
-mod util;
+mod filter;
+mod format;
+mod native;
+mod output;
+mod snapshot;
+mod summary;
+mod tree;
 
 use crate::app::App;
-use clap::{crate_version, Arg, Command};
+use crate::backend::{Chroot, Container, ExecutionBackend, Host};
+use crate::build::{BuildDatabase, BuildStep};
+use crate::command::MutCommand;
+use crate::filter::{Filter, DEFAULT_OPS, OPS};
+use crate::format::{Format, FORMATS};
+use crate::native::Native;
+use crate::output::CommandOutput;
+use crate::snapshot::{Rollback, Snapshot};
+use clap::parser::ValueSource;
+use clap::{crate_version, Arg, ArgAction, ArgMatches, Command};
 use dotenv::dotenv;
 use error::AppError;
+use glob::Pattern;
+use std::cell::RefCell;
+use std::fs::File;
+use std::os::unix::process::ExitStatusExt;
 
-const CHROOT_DIR: &str = "/usr/src/fusemount";
-const CONTAINER_NAME: &str = "build-env";
+const BACKENDS: [&str; 5] = ["docker", "podman", "chroot", "host", "native"];
 
 fn main() -> Result<(), AppError> {
     dotenv().ok();
 
-    let matches = Command::new("Cairn")
-        .author("xelahalo <xelahalo@gmail.com>")
-        .version(crate_version!())
-        .about("Tracing tool for forward build systems.")
-        // .arg(Arg::new("options").long("options").help(
-        //     "Characters to filter which operations to dump; a combination of r, w, m ,d, q, t",
-        // ).num_args(1).required(true))
-        // .arg(
-        //     Arg::new("output")
-        //         .help("Output file to write to")
-        //         .long("output")
-        //         .num_args(1)
-        //         .required(true),
-        // )
-        .arg(
-            Arg::new("cmd")
-                .help("Command to run in the build environment. Must be quoted.")
-                .num_args(1)
-                .required(true)
-                .allow_hyphen_values(true),
-        )
-        .get_matches();
-
-    // let mut options = String::new();
-    // if let Some(opts) = matches.get_one::<String>("options") {
-    //     options.push_str(opts.as_str())
-    // }
-
-    // let mut output_path = String::new();
-    // if let Some(path) = matches.get_one::<String>("output") {
-    //     output_path.push_str(path.as_str())
-    // }
-
-    let parsed_cmd: &str = match matches.get_one::<String>("cmd") {
-        Some(cmd) => cmd,
-        None => panic!("No command provided"),
+    let matches =
+        Command::new("Cairn")
+            .author("xelahalo <xelahalo@gmail.com>")
+            .version(crate_version!())
+            .about("Tracing tool for forward build systems.")
+            .subcommand_negates_reqs(true)
+            .subcommand(Command::new("rollback").about(
+                "Restore the files changed by the last traced command to their original state",
+            ))
+            .subcommand(
+                Command::new("build")
+                    .about("Run the commands of a build script in order and skip the ones whose inputs did not change since they last ran")
+                    .arg(
+                        Arg::new("script")
+                            .help("File with a shell command per line, lines starting with `#` are comments and a `\\` at the end of a line continues the command in the next one")
+                            .required(true),
+                    ),
+            )
+            .arg(
+                Arg::new("options")
+                    .long("options")
+                    .help("Characters of the operations to dump; a combination of r, w, m, d, q, t, c, n")
+                    .num_args(1)
+                    .value_parser(|options: &str| {
+                        match options.chars().find(|op| !OPS.contains(*op)) {
+                            Some(op) => Err(format!("unknown operation `{}`", op)),
+                            None => Ok(options.to_string()),
+                        }
+                    })
+                    .default_value(DEFAULT_OPS),
+            )
+            .arg(
+                Arg::new("output")
+                    .long("output")
+                    .help("File to write the trace of the command to, `-` for stdout")
+                    .num_args(1)
+                    .default_value("cairn.log"),
+            )
+            .arg(
+                Arg::new("format")
+                    .long("format")
+                    .help("How to write the trace: text for op|path lines, json for an object per line, csv, a make or ninja depfile of what the command wrote and read, or a summary of its inputs, outputs, temporaries, probes and deleted paths")
+                    .value_parser(FORMATS)
+                    .default_value("text"),
+            )
+            .arg(
+                Arg::new("tree")
+                    .long("tree")
+                    .help("Write the processes of the command as a tree, each with the operations it performed, instead of the trace")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("format"),
+            )
+            .arg(
+                Arg::new("include")
+                    .long("include")
+                    .help("Only dump operations on paths that match this glob, can be given more than once")
+                    .num_args(1)
+                    .value_parser(|glob: &str| Pattern::new(glob))
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                Arg::new("exclude")
+                    .long("exclude")
+                    .help("Leave out operations on paths that match this glob, like `/usr/**`, can be given more than once")
+                    .num_args(1)
+                    .value_parser(|glob: &str| Pattern::new(glob))
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                Arg::new("cmd")
+                    .help("Command to run in the build environment and its arguments, passed on as they are. Put it after `--` if it has options of its own")
+                    .num_args(1..)
+                    .required(true)
+                    .trailing_var_arg(true)
+                    .allow_hyphen_values(true),
+            )
+            .arg(
+                Arg::new("shell")
+                    .long("shell")
+                    .help("Run the command with `bash -c`, so that it can use shell syntax like pipes, redirections and `&&`")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("tee")
+                    .long("tee")
+                    .help("Also write the output of the command, stdout and stderr, to this file")
+                    .num_args(1),
+            )
+            .arg(
+                Arg::new("backend")
+                    .long("backend")
+                    .env("BACKEND")
+                    .help("Where to run the command: docker or podman run it in the container of init.sh, chroot and host use a cairn-fuse mount on this host, with or without chrooting into it, native mounts one itself and needs /dev/fuse and unprivileged user namespaces")
+                    .value_parser(BACKENDS)
+                    .default_value("docker"),
+            )
+            .arg(
+                Arg::new("container")
+                    .long("container")
+                    .env("CONTAINER_NAME")
+                    .help("Name of the container for the docker and podman backends")
+                    .num_args(1)
+                    .default_value("build-env"),
+            )
+            .arg(
+                Arg::new("mount-point")
+                    .long("mount-point")
+                    .env("MOUNT_POINT")
+                    .help("Where cairn-fuse is mounted for the chroot and host backends")
+                    .num_args(1)
+                    .required_if_eq_any([("backend", "chroot"), ("backend", "host")]),
+            )
+            .get_matches();
+
+    if matches.subcommand_matches("rollback").is_some() {
+        let log_dir = std::env::var("LOG_DIR").expect("ERROR: LOG_DIR not set");
+        let mnt_dir = std::env::var("MNT_DIR").expect("ERROR: MNT_DIR not set");
+        let rollback = Rollback::new(Snapshot::new(&format!("{}/snapshot", log_dir)), &mnt_dir);
+
+        let mut app = App::new(vec![Box::new(rollback)]);
+        app.execute()?;
+
+        return Ok(());
     }
-    .trim_matches(|c| c == '\'' || c == '"');
 
     let workdir = std::env::var("WORKDIR").expect("ERROR: WORKDIR not set");
 
-    // create log file then cd into folder where we run command
-    let cmd = command::Command::new(
-        "docker",
-        vec![
-            "exec",
-            CONTAINER_NAME,
-            "/bin/bash",
-            "-c",
-            format!(
-                "./command_wrapper.sh {} {} {}",
-                CHROOT_DIR, workdir, parsed_cmd
+    let output = CommandOutput::new(matches.get_one::<String>("tee").map(String::as_str))?;
+    let globs = |name: &str| -> Vec<Pattern> {
+        matches
+            .get_many::<Pattern>(name)
+            .map(|globs| globs.cloned().collect())
+            .unwrap_or_default()
+    };
+    let format = if matches.get_flag("tree") {
+        Format::Tree
+    } else {
+        Format::from_name(matches.get_one::<String>("format").unwrap())
+    };
+
+    let mut ops = matches.get_one::<String>("options").unwrap().clone();
+    // the summary tells probes apart by the lookups that found nothing
+    if format == Format::Summary
+        && matches.value_source("options") == Some(ValueSource::DefaultValue)
+    {
+        ops.push('n');
+    }
+    let filter = Filter::new(&ops, globs("include"), globs("exclude"));
+    let output_path = matches.get_one::<String>("output").unwrap();
+
+    if let Some(build) = matches.subcommand_matches("build") {
+        let log_dir = std::env::var("LOG_DIR").expect("ERROR: LOG_DIR not set");
+        let mnt_dir = std::env::var("MNT_DIR").expect("ERROR: MNT_DIR not set");
+        let lines = build::read_script(build.get_one::<String>("script").unwrap())?;
+        let database = RefCell::new(BuildDatabase::open(
+            &format!("{}/build.json", log_dir),
+            &mnt_dir,
+        )?);
+
+        // the commands add their traces to the output one after another
+        if output_path != "-" {
+            File::create(output_path)?;
+        }
+        let mut steps: Vec<Box<dyn MutCommand>> = Vec::new();
+        for (index, line) in lines.iter().enumerate() {
+            let argv = vec!["/bin/bash".to_string(), "-c".to_string(), line.clone()];
+            let cmd = command::Command::new(
+                backend(&matches)?,
+                &workdir,
+                argv,
+                output.try_clone()?,
+                filter.clone(),
+                format,
+                output_path,
             )
-            .as_str(),
-        ],
-        "cairn.log",
+            .append_output();
+            steps.push(Box::new(BuildStep::new(
+                cmd,
+                &workdir,
+                line,
+                (index + 1, lines.len()),
+                &database,
+            )));
+        }
+
+        // the build stops at the first command that fails
+        let mut app = App::new(steps);
+        return exit_like_command(app.execute());
+    }
+
+    let args: Vec<String> = match matches.get_many::<String>("cmd") {
+        Some(args) => args.cloned().collect(),
+        None => panic!("No command provided"),
+    };
+    let argv = if matches.get_flag("shell") {
+        vec!["/bin/bash".to_string(), "-c".to_string(), args.join(" ")]
+    } else {
+        args
+    };
+
+    let cmd = command::Command::new(
+        backend(&matches)?,
+        &workdir,
+        argv,
+        output,
+        filter,
+        format,
+        output_path,
     );
 
     let mut app = App::new(vec![Box::new(cmd)]);
+    exit_like_command(app.execute())
+}
+
+// Exits like the traced command did, if it failed, so that cairn can take its place in scripts
+fn exit_like_command(result: Result<(), AppError>) -> Result<(), AppError> {
+    match result {
+        Err(AppError::CommandFailed(status)) => std::process::exit(
+            status
+                .code()
+                .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)),
+        ),
+        result => result,
+    }
+}
 
-    app.execute()?;
+fn backend(matches: &ArgMatches) -> Result<Box<dyn ExecutionBackend>, AppError> {
+    let mnt_dir = || std::env::var("MNT_DIR").expect("ERROR: MNT_DIR not set");
+    let mount_point = || matches.get_one::<String>("mount-point").unwrap();
 
-    Ok(())
+    Ok(
+        match matches.get_one::<String>("backend").unwrap().as_str() {
+            "chroot" => Box::new(Chroot::new(&mnt_dir(), mount_point())?),
+            "host" => Box::new(Host::new(&mnt_dir(), mount_point())?),
+            "native" => {
+                let log_dir = std::env::var("LOG_DIR").expect("ERROR: LOG_DIR not set");
+                Box::new(Native::new(&mnt_dir(), &log_dir)?)
+            }
+            engine => {
+                let log_dir = std::env::var("LOG_DIR").expect("ERROR: LOG_DIR not set");
+                Box::new(Container::new(
+                    engine,
+                    matches.get_one::<String>("container").unwrap(),
+                    &log_dir,
+                ))
+            }
+        },
+    )
 }
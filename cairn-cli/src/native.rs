@@ -0,0 +1,275 @@
+use crate::backend::{chrooted, join_session, ExecutionBackend, Exit};
+use crate::error::AppError;
+use crate::output::CommandOutput;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Stdio};
+use std::sync::OnceLock;
+
+// the same directories that startup.sh mounts into the container
+const BIND_DIRS: [&str; 14] = [
+    "proc",
+    "sys",
+    "dev",
+    "bin",
+    "etc",
+    "lib",
+    "lib32",
+    "lib64",
+    "libx32",
+    "usr/lib",
+    "usr/lib32",
+    "usr/lib64",
+    "usr/libx32",
+    "usr/include",
+];
+const READY_MESSAGE: &str = "READY=1";
+
+// Runs a command on the host the way the docker container does, without docker.
+//
+// cairn-cli moves itself into a new user and mount namespace, in which it is allowed to mount
+// the workspace through cairn-fuse and to bind-mount the system directories next to it. The
+// command is then chrooted into the mount. Nothing leaks out of the namespace, so the host only
+// needs /dev/fuse and unprivileged user namespaces.
+#[derive(Debug)]
+pub struct Native {
+    // the workspace, which cairn-fuse serves
+    root: String,
+    log_dir: PathBuf,
+}
+
+impl Native {
+    pub fn new(workspace: &str, log_dir: &str) -> io::Result<Self> {
+        // cairn-fuse is only started once the command runs, the directory tells cairn-cli that
+        // the command will run in a session
+        fs::create_dir_all(Path::new(log_dir).join("sessions"))?;
+
+        Ok(Self {
+            root: fs::canonicalize(workspace)?.to_string_lossy().to_string(),
+            log_dir: PathBuf::from(log_dir),
+        })
+    }
+}
+
+impl ExecutionBackend for Native {
+    fn root(&self) -> &str {
+        &self.root
+    }
+
+    fn run(
+        &self,
+        workdir: &str,
+        argv: &[String],
+        session: Option<&str>,
+        output: &CommandOutput,
+    ) -> Result<Exit, AppError> {
+        enter_namespaces()?;
+
+        let sandbox = Sandbox::mount(self)?;
+        let mut command = chrooted(&sandbox.mountpoint, workdir, argv)?;
+        let _cgroup = join_session(&mut command, session);
+        let (pid, status) = output.run(&mut command)?;
+
+        sandbox.unmount()?;
+        Ok(Exit {
+            pid: Some(pid),
+            status,
+        })
+    }
+}
+
+// The workspace mounted through cairn-fuse, everything is undone when it is dropped
+struct Sandbox {
+    mountpoint: PathBuf,
+    tracer: Child,
+    // bind mounts and the directories created for them, in the order they were made
+    mounts: Vec<PathBuf>,
+    created: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    fn mount(native: &Native) -> Result<Sandbox, AppError> {
+        let mountpoint = std::env::temp_dir().join(format!("cairn-{}", process::id()));
+        fs::create_dir_all(&mountpoint)?;
+
+        let (ready_read, ready_write) = pipe()?;
+        let mut ready = unsafe { File::from_raw_fd(ready_read) };
+
+        let mut command = process::Command::new(cairn_fuse());
+        command
+            .arg("--ready-fd")
+            .arg(ready_write.to_string())
+            .arg("--trace-file")
+            .arg(native.log_dir.join("tracer.log"))
+            .arg("--backup-dir")
+            .arg(native.log_dir.join("backup"))
+            .arg("--session-dir")
+            .arg(native.log_dir.join("sessions"))
+            .arg("--log-file")
+            .arg(native.log_dir.join("cairn-fuse.log"))
+            .arg(&native.root)
+            .arg(&mountpoint)
+            .stdin(Stdio::null());
+        unsafe {
+            // pipes are created close-on-exec, only cairn-fuse gets to keep the write end
+            command.pre_exec(move || {
+                if libc::fcntl(ready_write, libc::F_SETFD, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let tracer = command.spawn();
+        // otherwise the read below would never see EOF
+        unsafe { libc::close(ready_write) };
+        let tracer = tracer?;
+
+        let mut sandbox = Sandbox {
+            mountpoint,
+            tracer,
+            mounts: Vec::new(),
+            created: Vec::new(),
+        };
+
+        // EOF means that cairn-fuse exited before mounting
+        let mut status = String::new();
+        ready.read_to_string(&mut status)?;
+        if status.trim() != READY_MESSAGE {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "cairn-fuse failed to start, see cairn-fuse.log",
+            )
+            .into());
+        }
+
+        for dir in BIND_DIRS {
+            let source = Path::new("/").join(dir);
+            if source.is_dir() {
+                sandbox.bind(&source, dir)?;
+            }
+        }
+
+        Ok(sandbox)
+    }
+
+    fn bind(&mut self, source: &Path, dir: &str) -> io::Result<()> {
+        let target = self.mountpoint.join(dir);
+
+        let mut current = self.mountpoint.clone();
+        for component in Path::new(dir).components() {
+            current.push(component);
+            if !current.exists() {
+                fs::create_dir(&current)?;
+                self.created.push(current.clone());
+            }
+        }
+
+        // recursive, because mounts below /proc or /dev that are locked in the user namespace
+        // can not be left out
+        mount(Some(source), &target, libc::MS_BIND | libc::MS_REC)?;
+        self.mounts.push(target);
+        Ok(())
+    }
+
+    fn unmount(mut self) -> io::Result<()> {
+        self.release()
+    }
+
+    fn release(&mut self) -> io::Result<()> {
+        // the bind mounts keep the fuse mount busy, so they have to go first
+        while let Some(target) = self.mounts.pop() {
+            let target = CString::new(target.as_os_str().as_bytes())?;
+            unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) };
+        }
+        while let Some(dir) = self.created.pop() {
+            let _ = fs::remove_dir(dir);
+        }
+
+        // cairn-fuse flushes the trace and unmounts itself on SIGTERM
+        if self.tracer.try_wait()?.is_none() {
+            unsafe { libc::kill(self.tracer.id() as i32, libc::SIGTERM) };
+            self.tracer.wait()?;
+        }
+        let _ = fs::remove_dir(&self.mountpoint);
+
+        Ok(())
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+// Moves the process into a new user namespace, in which it is root, and a new mount namespace
+// owned by it. Must be called while the process has a single thread. Only the first call does
+// so, every command of a build runs in the same namespaces, user namespaces can only be nested
+// 32 deep.
+fn enter_namespaces() -> io::Result<()> {
+    static ENTERED: OnceLock<io::Result<()>> = OnceLock::new();
+
+    match ENTERED.get_or_init(unshare_namespaces) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+    }
+}
+
+fn unshare_namespaces() -> io::Result<()> {
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+
+    if unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    fs::write("/proc/self/setgroups", "deny")?;
+    fs::write("/proc/self/uid_map", format!("0 {} 1", uid))?;
+    fs::write("/proc/self/gid_map", format!("0 {} 1", gid))?;
+
+    // keep the mounts made from now on out of the parent namespace
+    mount(None, Path::new("/"), libc::MS_REC | libc::MS_PRIVATE)
+}
+
+fn mount(source: Option<&Path>, target: &Path, flags: libc::c_ulong) -> io::Result<()> {
+    let source = source
+        .map(|s| CString::new(s.as_os_str().as_bytes()))
+        .transpose()?;
+    let target = CString::new(target.as_os_str().as_bytes())?;
+
+    let result = unsafe {
+        libc::mount(
+            source.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+            target.as_ptr(),
+            std::ptr::null(),
+            flags,
+            std::ptr::null(),
+        )
+    };
+
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn pipe() -> io::Result<(RawFd, RawFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((fds[0], fds[1]))
+}
+
+// cairn-fuse is installed next to cairn-cli, otherwise it is looked up in PATH
+fn cairn_fuse() -> PathBuf {
+    match std::env::current_exe() {
+        Ok(exe) if exe.with_file_name("cairn-fuse").exists() => exe.with_file_name("cairn-fuse"),
+        _ => PathBuf::from("cairn-fuse"),
+    }
+}
@@ -0,0 +1,81 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::process::{self, ExitStatus, Stdio};
+use std::thread;
+
+// Where the output of the traced command goes. It always reaches the terminal unchanged, and a
+// copy of it can be kept in a file as well.
+#[derive(Debug)]
+pub struct CommandOutput {
+    tee: Option<File>,
+}
+
+impl CommandOutput {
+    // `tee` is the file that gets a copy of both stdout and stderr, it is truncated first
+    pub fn new(tee: Option<&str>) -> io::Result<Self> {
+        let tee = tee
+            .map(|path| {
+                OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(path)
+            })
+            .transpose()?;
+
+        Ok(Self { tee })
+    }
+
+    // Another output to the same terminal and tee file, for commands that run one after another
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            tee: self.tee.as_ref().map(File::try_clone).transpose()?,
+        })
+    }
+
+    // Spawns `command` and waits for it, returns its pid and how it exited
+    pub fn run(&self, command: &mut process::Command) -> io::Result<(u32, ExitStatus)> {
+        let tee = match &self.tee {
+            Some(tee) => tee,
+            // nothing has to be copied, so the child writes to the terminal itself
+            None => {
+                let mut child = command.spawn()?;
+                return Ok((child.id(), child.wait()?));
+            }
+        };
+
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+
+        thread::scope(|scope| {
+            let out = scope.spawn(|| copy(stdout, io::stdout(), tee));
+            let err = scope.spawn(|| copy(stderr, io::stderr(), tee));
+            out.join().unwrap()?;
+            err.join().unwrap()
+        })?;
+
+        Ok((child.id(), child.wait()?))
+    }
+}
+
+// Copies everything from `source` to both `terminal` and `tee` as it arrives, without any
+// buffering, so that output is not held back and stays in order with the other stream
+fn copy(mut source: impl Read, mut terminal: impl Write, mut tee: &File) -> io::Result<()> {
+    let mut buffer = [0; 8192];
+    loop {
+        let read = match source.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        terminal.write_all(&buffer[..read])?;
+        terminal.flush()?;
+        tee.write_all(&buffer[..read])?;
+    }
+}
@@ -0,0 +1,222 @@
+use crate::command::MutCommand;
+use crate::error::AppError;
+use std::collections::BTreeSet;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, FileTimes};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs as ufs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+// cairn-fuse leaves a file with this prefix next to where a path would be, if the path did not
+// exist before the traced command created it
+const WHITEOUT_PREFIX: &str = ".wh.";
+const MANIFEST: &str = "manifest";
+const FILES: &str = "files";
+
+// The state of the paths changed by the last traced command, from before it ran.
+//
+// The pre-images are taken from the backup that cairn-fuse keeps, only the ones of paths that
+// show up in the trace of the command are moved into the snapshot. The manifest lists those
+// paths relative to the traced root, restoring a directory restores what was saved inside it.
+pub struct Snapshot {
+    dir: PathBuf,
+}
+
+impl Snapshot {
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    // Replaces the snapshot with the pre-images of `paths` from `backup`
+    pub fn take(&self, backup: &Path, mut paths: Vec<PathBuf>) -> Result<(), AppError> {
+        remove_all(&self.dir)?;
+        let files = self.dir.join(FILES);
+        fs::create_dir_all(&files)?;
+
+        // parents come first, a saved directory already contains the paths below it
+        paths.sort();
+        paths.dedup();
+
+        let mut recorded: Vec<PathBuf> = Vec::new();
+        for path in paths {
+            if recorded.iter().any(|r| path.starts_with(r)) {
+                continue;
+            }
+
+            let source = backup.join(&path);
+            let target = files.join(&path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            if whiteout_path(&source).exists() {
+                fs::rename(whiteout_path(&source), whiteout_path(&target))?;
+            } else if source.symlink_metadata().is_ok() {
+                fs::rename(&source, &target)?;
+            } else {
+                eprintln!(
+                    "No backup of {}, it will not be rolled back",
+                    path.display()
+                );
+                continue;
+            }
+
+            recorded.push(path);
+        }
+
+        let mut manifest = File::create(self.dir.join(MANIFEST))?;
+        for path in recorded {
+            writeln!(&mut manifest, "{}", path.display())?;
+        }
+
+        Ok(())
+    }
+
+    // Makes this the snapshot that `latest` links to, which is the one a rollback restores. The
+    // snapshot that `latest` linked to before is discarded.
+    pub fn make_latest(&self, latest: &Path) -> Result<(), AppError> {
+        let parent = latest.parent().unwrap_or(Path::new(""));
+        let previous = fs::read_link(latest).ok().map(|target| parent.join(target));
+        // relative, so that the link works on both sides of the log directory of a container
+        let target = self.dir.strip_prefix(parent).unwrap_or(&self.dir);
+
+        // the link is replaced in one go, a rollback never sees it missing
+        let link = latest.with_extension(process::id().to_string());
+        remove_all(&link)?;
+        ufs::symlink(target, &link)?;
+        if !latest.is_symlink() {
+            remove_all(latest)?;
+        }
+        fs::rename(&link, latest)?;
+
+        if let Some(previous) = previous.filter(|previous| *previous != self.dir) {
+            remove_all(&previous)?;
+        }
+        Ok(())
+    }
+
+    // Puts every path of the snapshot back under `root` and discards the snapshot
+    pub fn restore(&self, root: &Path) -> Result<(), AppError> {
+        // the snapshot of a session is only linked to
+        let dir = fs::canonicalize(&self.dir)?;
+        let manifest = File::open(dir.join(MANIFEST))?;
+        let files = dir.join(FILES);
+
+        for line in BufReader::new(manifest).lines() {
+            let path = line?;
+            restore_entry(&files.join(&path), &root.join(&path))?;
+        }
+
+        remove_all(&dir)?;
+        remove_all(&self.dir)?;
+        Ok(())
+    }
+}
+
+// Removes the backup that cairn-fuse kept for a session, once its snapshot is taken
+pub fn remove_backup(backup: &Path) -> Result<(), AppError> {
+    Ok(remove_all(backup)?)
+}
+
+// Empties the backup of cairn-fuse, so that it only collects the pre-images of the next command
+pub fn clear_backup(backup: &Path) -> Result<(), AppError> {
+    if !backup.exists() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(backup)? {
+        remove_all(&entry?.path())?;
+    }
+
+    Ok(())
+}
+
+fn restore_entry(saved: &Path, target: &Path) -> io::Result<()> {
+    if whiteout_path(saved).exists() {
+        return remove_all(target);
+    }
+
+    let metadata = saved.symlink_metadata()?;
+    if metadata.is_dir() {
+        if !target
+            .symlink_metadata()
+            .map(|m| m.is_dir())
+            .unwrap_or(false)
+        {
+            remove_all(target)?;
+            fs::create_dir_all(target)?;
+        }
+        fs::set_permissions(target, metadata.permissions())?;
+
+        // anything that was not saved inside the directory has not changed
+        let mut names = BTreeSet::new();
+        for entry in fs::read_dir(saved)? {
+            let name = entry?.file_name();
+            match name.to_str().and_then(|n| n.strip_prefix(WHITEOUT_PREFIX)) {
+                Some(removed) => names.insert(OsString::from(removed)),
+                None => names.insert(name),
+            };
+        }
+
+        for name in names {
+            restore_entry(&saved.join(&name), &target.join(&name))?;
+        }
+
+        return Ok(());
+    }
+
+    remove_all(target)?;
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if metadata.file_type().is_symlink() {
+        return ufs::symlink(fs::read_link(saved)?, target);
+    }
+
+    fs::copy(saved, target)?;
+    // build tools compare timestamps, so the file has to look untouched
+    File::open(target)?.set_times(
+        FileTimes::new()
+            .set_accessed(metadata.accessed()?)
+            .set_modified(metadata.modified()?),
+    )
+}
+
+fn whiteout_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(WHITEOUT_PREFIX);
+    name.push(path.file_name().unwrap_or(OsStr::new("")));
+    path.with_file_name(name)
+}
+
+fn remove_all(path: &Path) -> io::Result<()> {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+pub struct Rollback {
+    snapshot: Snapshot,
+    root: PathBuf,
+}
+
+impl Rollback {
+    pub fn new(snapshot: Snapshot, root: &str) -> Self {
+        Self {
+            snapshot,
+            root: PathBuf::from(root),
+        }
+    }
+}
+
+impl MutCommand for Rollback {
+    fn execute(&mut self) -> Result<(), AppError> {
+        self.snapshot.restore(&self.root)
+    }
+}
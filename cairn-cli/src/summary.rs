@@ -0,0 +1,189 @@
+use crate::command::LogEntry;
+use std::collections::{BTreeMap, BTreeSet};
+
+// What a traced command did with the paths it touched, which is what a build system needs to
+// know about it
+#[derive(Debug, Default)]
+pub struct Summary<'a> {
+    // read before the command wrote them
+    pub inputs: BTreeSet<&'a str>,
+    // written and still there once the command exited
+    pub outputs: BTreeSet<&'a str>,
+    // created and deleted again by the command
+    pub temporaries: BTreeSet<&'a str>,
+    // looked for but not there, the result of the command may change once they are
+    pub probes: BTreeSet<&'a str>,
+    // there before the command and deleted by it
+    pub deleted: BTreeSet<&'a str>,
+}
+
+// What the trace tells about a single path
+#[derive(Clone, Debug, Default)]
+struct PathState {
+    // whether the path was there before the command, once the trace tells
+    existed: Option<bool>,
+    input: bool,
+    written: bool,
+    present: bool,
+    // whether the path is only known as a name that a file the command made was renamed to
+    made: bool,
+}
+
+impl PathState {
+    fn found(&mut self, existed: bool) {
+        self.existed.get_or_insert(existed);
+        self.present = existed;
+    }
+}
+
+impl<'a> Summary<'a> {
+    // `entries` have to be in the order they were traced. A path that the command created shows
+    // up in a lookup that found nothing first, cairn-fuse traces those as `n`.
+    pub fn new(entries: &[&'a LogEntry]) -> Summary<'a> {
+        let mut states: BTreeMap<&'a str, PathState> = BTreeMap::new();
+
+        for entry in entries {
+            let paths = entry.paths();
+            match (entry.op, paths.as_slice()) {
+                ('n', [path, ..]) => {
+                    let state = states.entry(path).or_default();
+                    state.found(false);
+                }
+                ('r', [path, ..]) => {
+                    let state = states.entry(path).or_default();
+                    state.found(true);
+                    // reading what it wrote itself does not make a path an input
+                    state.input |= !state.written;
+                }
+                ('w' | 'c' | 't', [path, ..]) => {
+                    let state = states.entry(path).or_default();
+                    state.existed.get_or_insert(true);
+                    state.written = true;
+                    state.present = true;
+                }
+                ('d', [path, ..]) => {
+                    let state = states.entry(path).or_default();
+                    state.found(true);
+                    state.present = false;
+                }
+                ('m', [source, destination, ..]) => {
+                    let moved = states.entry(source).or_default();
+                    moved.found(true);
+                    moved.present = false;
+                    // a file that the command wrote under another name first, like a temporary
+                    // file renamed to its final name, is only the destination, however many
+                    // names it went through
+                    let made = moved.existed == Some(false) || moved.made;
+                    if made {
+                        states.remove(source);
+                    }
+
+                    let state = states.entry(destination).or_default();
+                    state.made = made && state.existed.is_none();
+                    state.existed.get_or_insert(true);
+                    state.written = true;
+                    state.present = true;
+                }
+                _ => {}
+            }
+        }
+
+        let mut summary = Summary::default();
+        for (path, state) in states {
+            if state.input {
+                summary.inputs.insert(path);
+            }
+
+            match (state.existed, state.written, state.present) {
+                (_, true, true) => summary.outputs.insert(path),
+                (Some(false), true, false) => summary.temporaries.insert(path),
+                (Some(false), false, _) => summary.probes.insert(path),
+                (Some(true), _, false) => summary.deleted.insert(path),
+                _ => false,
+            };
+        }
+
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a name, the trace of a command and what it makes of the paths, as in `summarize`
+    type Case<'a> = (&'a str, &'a [(char, &'a str)], [&'a [&'a str]; 5]);
+
+    // The paths of `trace` by what the command did with them: inputs, outputs, temporaries,
+    // probes and deleted paths
+    fn summarize(trace: &[(char, &str)]) -> [Vec<String>; 5] {
+        let entries: Vec<LogEntry> = trace
+            .iter()
+            .map(|(op, path)| LogEntry::new(*op, path))
+            .collect();
+        let entries: Vec<&LogEntry> = entries.iter().collect();
+        let summary = Summary::new(&entries);
+
+        let names = |paths: &BTreeSet<&str>| paths.iter().map(|path| path.to_string()).collect();
+        [
+            names(&summary.inputs),
+            names(&summary.outputs),
+            names(&summary.temporaries),
+            names(&summary.probes),
+            names(&summary.deleted),
+        ]
+    }
+
+    #[test]
+    fn paths_are_told_apart_by_what_the_command_did() {
+        let none: &[&str] = &[];
+        let cases: [Case; 7] = [
+            (
+                "read then written",
+                &[('r', "/a"), ('w', "/a")],
+                [&["/a"], &["/a"], none, none, none],
+            ),
+            (
+                "written then read",
+                &[('n', "/a"), ('c', "/a"), ('r', "/a")],
+                [none, &["/a"], none, none, none],
+            ),
+            (
+                "created then deleted",
+                &[('n', "/a.tmp"), ('c', "/a.tmp"), ('d', "/a.tmp")],
+                [none, none, &["/a.tmp"], none, none],
+            ),
+            (
+                "probe",
+                &[('n', "/a.h"), ('r', "/b.h")],
+                [&["/b.h"], none, none, &["/a.h"], none],
+            ),
+            (
+                "temporary renamed to its final name",
+                &[('n', "/a.tmp"), ('c', "/a.tmp"), ('m', "/a.tmp|/a")],
+                [none, &["/a"], none, none, none],
+            ),
+            (
+                "rename chain",
+                &[
+                    ('n', "/a.1"),
+                    ('c', "/a.1"),
+                    ('m', "/a.1|/a.2"),
+                    ('m', "/a.2|/a"),
+                ],
+                [none, &["/a"], none, none, none],
+            ),
+            (
+                "existing file renamed and deleted",
+                &[('m', "/a|/b"), ('d', "/b")],
+                [none, none, none, none, &["/a", "/b"]],
+            ),
+        ];
+
+        for (name, trace, expected) in cases {
+            let expected: [Vec<String>; 5] =
+                expected.map(|paths| paths.iter().map(|path| path.to_string()).collect());
+            assert_eq!(summarize(trace), expected, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use crate::command::LogEntry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Write};
+
+// The processes that show up in the trace, by the parent that started them
+pub struct ProcessTree {
+    root: u32,
+    // in the order the children first show up in the trace
+    children: HashMap<u32, Vec<u32>>,
+    parents: HashMap<u32, u32>,
+}
+
+impl ProcessTree {
+    pub fn new(root: u32) -> Self {
+        Self {
+            root,
+            children: HashMap::new(),
+            parents: HashMap::new(),
+        }
+    }
+
+    // Adds the process of `entry`, the first entry of a process decides its parent
+    pub fn add(&mut self, entry: &LogEntry) {
+        // -1 if cairn-fuse could not find out the parent
+        if entry.ppid < 0 || entry.pid == self.root || self.parents.contains_key(&entry.pid) {
+            return;
+        }
+        self.parents.insert(entry.pid, entry.ppid as u32);
+        self.children
+            .entry(entry.ppid as u32)
+            .or_default()
+            .push(entry.pid);
+    }
+
+    // The root and every process below it, however late its parent shows up in the trace
+    pub fn descendants(&self) -> HashSet<u32> {
+        let mut found = HashSet::from([self.root]);
+        let mut queue = VecDeque::from([self.root]);
+
+        while let Some(pid) = queue.pop_front() {
+            for child in self.children.get(&pid).into_iter().flatten() {
+                if found.insert(*child) {
+                    queue.push_back(*child);
+                }
+            }
+        }
+
+        found
+    }
+
+    // Writes every process below the root with the operations it performed, `entries` have to
+    // be the ones of the tree. Processes of the entries that are not below the root, like the
+    // daemons of a session that were reparented to init, follow as trees of their own.
+    pub fn write(&self, out: &mut dyn Write, entries: &[&LogEntry]) -> io::Result<()> {
+        let mut operations: HashMap<u32, Vec<&LogEntry>> = HashMap::new();
+        for entry in entries {
+            operations.entry(entry.pid).or_default().push(entry);
+        }
+
+        let mut written = HashSet::new();
+        self.write_below(out, self.root, &operations, &mut written)?;
+
+        for entry in entries {
+            // from the topmost process that is not written yet and performed operations itself
+            let mut top = entry.pid;
+            let mut visited = HashSet::from([top]);
+            while let Some(parent) = self.parents.get(&top) {
+                if !operations.contains_key(parent)
+                    || written.contains(parent)
+                    || !visited.insert(*parent)
+                {
+                    break;
+                }
+                top = *parent;
+            }
+            self.write_below(out, top, &operations, &mut written)?;
+        }
+
+        Ok(())
+    }
+
+    // Writes `top` and every process below it that is not in `written` yet
+    fn write_below(
+        &self,
+        out: &mut dyn Write,
+        top: u32,
+        operations: &HashMap<u32, Vec<&LogEntry>>,
+        written: &mut HashSet<u32>,
+    ) -> io::Result<()> {
+        // depth first, with the depth of every process next to it. Reused pids can make a
+        // process show up below itself, it is only written once.
+        let mut stack = vec![(top, 0)];
+        while let Some((pid, depth)) = stack.pop() {
+            if !written.insert(pid) {
+                continue;
+            }
+
+            let indent = "  ".repeat(depth);
+            writeln!(out, "{}[{}]", indent, pid)?;
+            for entry in operations.get(&pid).into_iter().flatten() {
+                writeln!(out, "{}  {}|{}", indent, entry.op, entry.path)?;
+            }
+
+            let children = self.children.get(&pid).into_iter().flatten();
+            stack.extend(children.rev().map(|child| (*child, depth + 1)));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(pid: u32, ppid: i32) -> LogEntry {
+        let mut entry = LogEntry::new('r', "/a");
+        entry.pid = pid;
+        entry.ppid = ppid;
+        entry
+    }
+
+    #[test]
+    fn descendants_are_found_whatever_order_they_are_traced_in() {
+        let mut tree = ProcessTree::new(10);
+        // the grandchild shows up before the child that started it
+        for (pid, ppid) in [(12, 11), (13, 12), (11, 10), (20, 1), (14, -1)] {
+            tree.add(&entry(pid, ppid));
+        }
+
+        assert_eq!(tree.descendants(), HashSet::from([10, 11, 12, 13]));
+    }
+}
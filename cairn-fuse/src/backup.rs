@@ -0,0 +1,174 @@
+use crate::overlay::whiteout_path;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs as ufs;
+use std::os::unix::prelude::MetadataExt;
+use std::path::{Path, PathBuf};
+
+// Keeps the pre-image of every path that the traced commands change, so that the changes can
+// be rolled back afterwards.
+//
+// The backup mirrors the tree under `root`. The first time a path is about to change, its
+// current state is copied to the same place in the backup, or a whiteout is left there if the
+// path does not exist yet. Later changes keep the first copy, until the backup is cleared.
+// Timestamps of files are kept, the ones of directories are not. The changes of a session are
+// saved to a backup of their own, under `<dir>/<session>`, so that sessions that run at the same
+// time can not take the pre-images of each other.
+pub struct Backup {
+    root: PathBuf,
+    dir: PathBuf,
+}
+
+impl Backup {
+    pub fn new(root: &str, dir: PathBuf) -> io::Result<Backup> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(Backup {
+            root: PathBuf::from(root),
+            dir,
+        })
+    }
+
+    fn target(&self, path: &Path, session: Option<&str>) -> io::Result<PathBuf> {
+        let dir = match session {
+            Some(session) => self.dir.join(session),
+            None => self.dir.clone(),
+        };
+        path.strip_prefix(&self.root)
+            .map(|relative| dir.join(relative))
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+    }
+
+    // Saves `path` and the directories above it for `session`, unless they were saved already
+    pub fn save(&self, path: &Path, session: Option<&str>) -> io::Result<()> {
+        let ancestors: Vec<&Path> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|ancestor| *ancestor != self.root && ancestor.starts_with(&self.root))
+            .collect();
+
+        // from the top, so that every directory is saved before something is put into it
+        for ancestor in ancestors.into_iter().rev() {
+            self.save_entry(ancestor, session)?;
+        }
+
+        self.save_entry(path, session)?;
+        Ok(())
+    }
+
+    // Saves `path` together with everything below it, used before a directory is moved away
+    pub fn save_tree(&self, path: &Path, session: Option<&str>) -> io::Result<()> {
+        self.save(path, session)?;
+
+        if whiteout_path(&self.target(path, session)?).exists() {
+            return Ok(());
+        }
+
+        match path.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => {
+                for entry in fs::read_dir(path)? {
+                    self.save_tree(&path.join(entry?.file_name()), session)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Copies a single entry, directories are saved without their contents
+    fn save_entry(&self, path: &Path, session: Option<&str>) -> io::Result<()> {
+        let target = self.target(path, session)?;
+        let absent = whiteout_path(&target);
+        if target.symlink_metadata().is_ok() || absent.exists() {
+            return Ok(());
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let metadata = match path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                File::create(absent)?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        if metadata.file_type().is_symlink() {
+            return ufs::symlink(fs::read_link(path)?, &target);
+        }
+
+        if metadata.is_dir() {
+            fs::create_dir(&target)?;
+            fs::set_permissions(&target, metadata.permissions())
+        } else {
+            fs::copy(path, &target)?;
+            utime::set_file_times(&target, metadata.atime(), metadata.mtime())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Backup;
+    use std::fs;
+
+    #[test]
+    fn only_the_first_state_is_kept() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("src")).unwrap();
+        fs::write(root.path().join("src/a.c"), "before").unwrap();
+
+        let backup = Backup::new(root.path().to_str().unwrap(), dir.path().to_path_buf()).unwrap();
+        let src = root.path().join("src");
+
+        backup.save(&src.join("a.c"), None).unwrap();
+        fs::write(src.join("a.c"), "after").unwrap();
+        backup.save(&src.join("a.c"), None).unwrap();
+        backup.save(&src.join("b.c"), None).unwrap();
+        fs::write(src.join("b.c"), "new").unwrap();
+        backup.save(&src.join("b.c"), None).unwrap();
+
+        let saved = dir.path().join("src");
+        assert_eq!(fs::read_to_string(saved.join("a.c")).unwrap(), "before");
+        assert!(saved.join(".wh.b.c").exists());
+        assert!(!saved.join("b.c").exists());
+    }
+
+    #[test]
+    fn save_tree_copies_the_whole_directory() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("out/obj")).unwrap();
+        fs::write(root.path().join("out/obj/a.o"), "object").unwrap();
+
+        let backup = Backup::new(root.path().to_str().unwrap(), dir.path().to_path_buf()).unwrap();
+        backup.save_tree(&root.path().join("out"), None).unwrap();
+
+        let saved = dir.path().join("out/obj/a.o");
+        assert_eq!(fs::read_to_string(saved).unwrap(), "object");
+    }
+
+    #[test]
+    fn sessions_keep_their_own_pre_images() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("a.c"), "before").unwrap();
+
+        let backup = Backup::new(root.path().to_str().unwrap(), dir.path().to_path_buf()).unwrap();
+        let file = root.path().join("a.c");
+
+        backup.save(&file, Some("build-1")).unwrap();
+        fs::write(&file, "changed by build-1").unwrap();
+        // a session that starts later sees the file as build-1 left it
+        backup.save(&file, Some("build-2")).unwrap();
+
+        let saved = |session: &str| fs::read_to_string(dir.path().join(session).join("a.c"));
+        assert_eq!(saved("build-1").unwrap(), "before");
+        assert_eq!(saved("build-2").unwrap(), "changed by build-1");
+        assert!(!dir.path().join("a.c").exists());
+    }
+}
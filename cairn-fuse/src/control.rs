@@ -0,0 +1,420 @@
+use crate::session::SessionSink;
+use crate::{time_now, DirEntry, DirSnapshot, FileKind, InodeAttributes};
+use fuser::FUSE_ROOT_ID;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::ops::Range;
+use std::os::raw::c_int;
+use std::os::unix::fs::FileExt;
+use std::sync::Mutex;
+
+// the name of the control directory in the root of the mount
+pub const CONTROL_DIR: &str = ".cairn";
+// every operation that is traced, in the order they are listed in the stats
+pub const OPS: &str = "rwmdqtcn";
+
+// The inodes from here on up to 1 << 63 are never the ones of real files. The control directory
+// takes the lower half of them, real files whose inodes fall into them are given ones from the
+// upper half instead, see Layers::inode.
+pub const RESERVED_INOS: Range<u64> = (1 << 62)..(1 << 63);
+pub const FIRST_REMAPPED_INO: u64 = RESERVED_INOS.start + (1 << 61);
+
+const CONTROL_INO: u64 = RESERVED_INOS.start;
+const SESSION_INO: u64 = CONTROL_INO + 1;
+const NEW_SESSION_INO: u64 = CONTROL_INO + 2;
+const STATS_INO: u64 = CONTROL_INO + 3;
+const SESSIONS_INO: u64 = CONTROL_INO + 4;
+// every session gets two inodes from here on, one for its directory and one for its events
+const FIRST_SESSION_INO: u64 = CONTROL_INO + 16;
+
+// A directory of synthetic files in the root of the mount, through which the processes inside
+// of it can control and query the tracer:
+//
+//   /.cairn/session/new            write a pid to it to start a session for that process and
+//                                  everything it starts, then read the id of the session back
+//   /.cairn/stats                  how many operations of every kind were traced so far
+//   /.cairn/sessions/<id>/events   the events of a session, in the format of the trace file
+//
+// Nothing in here is ever traced, and the directory is not listed in the root.
+pub struct Control {
+    uid: u32,
+    gid: u32,
+    // the ids of the sessions that were looked up, by their position in the inodes
+    sessions: Vec<String>,
+    handles: HashMap<u64, Handle>,
+}
+
+// An open file of the control directory. Reads carry on from where the last one stopped, the
+// offset of the kernel means nothing for these files.
+struct Handle {
+    ino: u64,
+    content: Vec<u8>,
+    events: Option<File>,
+    position: u64,
+}
+
+impl Control {
+    pub fn new() -> Control {
+        Control {
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            sessions: Vec::new(),
+            handles: HashMap::new(),
+        }
+    }
+
+    // Whether `ino` belongs to the control directory
+    pub fn owns(ino: u64) -> bool {
+        (CONTROL_INO..FIRST_REMAPPED_INO).contains(&ino)
+    }
+
+    // Whether the lookup of `name` in `parent` has to be answered by `lookup`
+    pub fn handles_lookup(parent: u64, name: &OsStr) -> bool {
+        Control::owns(parent) || (parent == FUSE_ROOT_ID && name == CONTROL_DIR)
+    }
+
+    pub fn lookup(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        sessions: Option<&Mutex<SessionSink>>,
+    ) -> Result<InodeAttributes, c_int> {
+        let name = name.to_str().ok_or(libc::ENOENT)?;
+        let ino = match (parent, name) {
+            (FUSE_ROOT_ID, CONTROL_DIR) => CONTROL_INO,
+            (CONTROL_INO, "session") => SESSION_INO,
+            (CONTROL_INO, "stats") => STATS_INO,
+            (CONTROL_INO, "sessions") => SESSIONS_INO,
+            (SESSION_INO, "new") => NEW_SESSION_INO,
+            (SESSIONS_INO, session) => {
+                let registered = registered_sessions(sessions)?;
+                if !registered.iter().any(|s| s == session) {
+                    return Err(libc::ENOENT);
+                }
+                self.session_ino(session)
+            }
+            (parent, "events") if self.session_of(parent).is_some() => parent + 1,
+            _ => return Err(libc::ENOENT),
+        };
+
+        self.attrs(ino)
+    }
+
+    pub fn attrs(&self, ino: u64) -> Result<InodeAttributes, c_int> {
+        let (kind, mode) = match ino {
+            CONTROL_INO | SESSION_INO | SESSIONS_INO => (FileKind::Directory, 0o555),
+            NEW_SESSION_INO => (FileKind::File, 0o666),
+            STATS_INO => (FileKind::File, 0o444),
+            ino if self.session_of(ino).is_some() => match (ino - FIRST_SESSION_INO) % 2 {
+                0 => (FileKind::Directory, 0o555),
+                _ => (FileKind::File, 0o444),
+            },
+            _ => return Err(libc::ENOENT),
+        };
+        let type_bits = match kind {
+            FileKind::Directory => libc::S_IFDIR,
+            _ => libc::S_IFREG,
+        };
+
+        Ok(InodeAttributes {
+            ino,
+            uid: self.uid,
+            gid: self.gid,
+            mode: type_bits | mode,
+            atime: time_now(),
+            mtime: time_now(),
+            kind,
+            // the size of the files is not known before they are read
+            len: 0,
+            nlinks: if kind == FileKind::Directory { 2 } else { 1 },
+            blksize: 4096,
+            blocks: 0,
+            rdev: 0,
+            real_path: String::new(),
+        })
+    }
+
+    // Lists the directory `ino` the way opendir() captures real directories
+    pub fn list(
+        &mut self,
+        ino: u64,
+        sessions: Option<&Mutex<SessionSink>>,
+    ) -> Result<DirSnapshot, c_int> {
+        let (parent, names): (u64, Vec<String>) = match ino {
+            CONTROL_INO => (
+                FUSE_ROOT_ID,
+                vec!["session".into(), "stats".into(), "sessions".into()],
+            ),
+            SESSION_INO => (CONTROL_INO, vec!["new".into()]),
+            SESSIONS_INO => (CONTROL_INO, registered_sessions(sessions)?),
+            ino if self.attrs(ino)?.kind == FileKind::Directory => {
+                (SESSIONS_INO, vec!["events".into()])
+            }
+            _ => return Err(libc::ENOTDIR),
+        };
+
+        let mut entries = vec![
+            DirEntry {
+                ino,
+                name: OsString::from("."),
+                attrs: self.attrs(ino)?,
+            },
+            DirEntry {
+                ino: parent,
+                name: OsString::from(".."),
+                // only the kind of the parent is used, and the root is a directory as well
+                attrs: self.attrs(CONTROL_INO)?,
+            },
+        ];
+        for name in names {
+            let attrs = self.lookup(ino, OsStr::new(&name), sessions)?;
+            entries.push(DirEntry {
+                ino: attrs.ino,
+                name: OsString::from(name),
+                attrs,
+            });
+        }
+
+        Ok(DirSnapshot {
+            real_path: String::new(),
+            entries,
+        })
+    }
+
+    // Opens the file `ino` as `fh`. The stats are taken when the file is opened.
+    pub fn open(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        stats: &Mutex<BTreeMap<char, u64>>,
+        sessions: Option<&Mutex<SessionSink>>,
+    ) -> Result<(), c_int> {
+        let mut handle = Handle {
+            ino,
+            content: Vec::new(),
+            events: None,
+            position: 0,
+        };
+
+        match ino {
+            NEW_SESSION_INO => {}
+            STATS_INO => {
+                let stats = stats.lock().unwrap();
+                for op in OPS.chars() {
+                    let count = stats.get(&op).copied().unwrap_or(0);
+                    handle
+                        .content
+                        .extend(format!("{} {}\n", op, count).into_bytes());
+                }
+            }
+            ino => {
+                let session = match self.session_of(ino) {
+                    Some(session) if self.attrs(ino)?.kind == FileKind::File => session,
+                    Some(_) => return Err(libc::EISDIR),
+                    None if Control::owns(ino) => return Err(libc::EISDIR),
+                    None => return Err(libc::ENOENT),
+                };
+                let sessions = sessions.ok_or(libc::ENOENT)?;
+                let events = sessions.lock().unwrap().events(session);
+                handle.events = Some(events.map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?);
+            }
+        }
+
+        self.handles.insert(fh, handle);
+        Ok(())
+    }
+
+    // Returns None if `fh` is not a file of the control directory
+    pub fn read(&mut self, fh: u64, size: u32) -> Option<Result<Vec<u8>, c_int>> {
+        let handle = self.handles.get_mut(&fh)?;
+
+        let data = match &handle.events {
+            Some(events) => {
+                let mut buffer = vec![0; size as usize];
+                match events.read_at(&mut buffer, handle.position) {
+                    Ok(read) => {
+                        buffer.truncate(read);
+                        buffer
+                    }
+                    Err(e) => return Some(Err(e.raw_os_error().unwrap_or(libc::EIO))),
+                }
+            }
+            None => {
+                let start = (handle.position as usize).min(handle.content.len());
+                let end = (start + size as usize).min(handle.content.len());
+                handle.content[start..end].to_vec()
+            }
+        };
+
+        handle.position += data.len() as u64;
+        Some(Ok(data))
+    }
+
+    // Returns None if `fh` is not a file of the control directory. Only new sessions can be
+    // written to, with the pid of the process to start the session for.
+    pub fn write(
+        &mut self,
+        fh: u64,
+        data: &[u8],
+        sessions: Option<&Mutex<SessionSink>>,
+    ) -> Option<Result<u32, c_int>> {
+        let handle = self.handles.get_mut(&fh)?;
+        if handle.ino != NEW_SESSION_INO {
+            return Some(Err(libc::EACCES));
+        }
+
+        let pid = match std::str::from_utf8(data).map(|pid| pid.trim().parse::<u32>()) {
+            Ok(Ok(pid)) => pid,
+            _ => return Some(Err(libc::EINVAL)),
+        };
+        let sessions = match sessions {
+            Some(sessions) => sessions,
+            // sessions need a directory to keep their events in
+            None => return Some(Err(libc::ENOTSUP)),
+        };
+
+        match sessions.lock().unwrap().start(pid) {
+            Ok(session) => {
+                // the id is read back from the same file, from its start
+                handle.content = format!("{}\n", session).into_bytes();
+                handle.position = 0;
+                Some(Ok(data.len() as u32))
+            }
+            Err(e) => Some(Err(e.raw_os_error().unwrap_or(libc::EIO))),
+        }
+    }
+
+    // Returns false if `fh` is not a file of the control directory
+    pub fn release(&mut self, fh: u64) -> bool {
+        self.handles.remove(&fh).is_some()
+    }
+
+    fn session_ino(&mut self, session: &str) -> u64 {
+        let index = match self.sessions.iter().position(|s| s == session) {
+            Some(index) => index,
+            None => {
+                self.sessions.push(session.to_string());
+                self.sessions.len() - 1
+            }
+        };
+
+        FIRST_SESSION_INO + 2 * index as u64
+    }
+
+    // The session that the directory or events inode `ino` belongs to
+    fn session_of(&self, ino: u64) -> Option<&str> {
+        let index = ino.checked_sub(FIRST_SESSION_INO)? / 2;
+        self.sessions.get(index as usize).map(String::as_str)
+    }
+}
+
+fn registered_sessions(sessions: Option<&Mutex<SessionSink>>) -> Result<Vec<String>, c_int> {
+    match sessions {
+        Some(sessions) => sessions
+            .lock()
+            .unwrap()
+            .sessions()
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO)),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn names(snapshot: &DirSnapshot) -> Vec<String> {
+        snapshot
+            .entries
+            .iter()
+            .map(|entry| entry.name.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn the_control_directory_is_found_in_the_root() {
+        let mut control = Control::new();
+
+        let attrs = control
+            .lookup(FUSE_ROOT_ID, OsStr::new(CONTROL_DIR), None)
+            .unwrap();
+        assert!(Control::owns(attrs.ino));
+        assert!(attrs.kind == FileKind::Directory);
+        assert!(!Control::owns(FIRST_REMAPPED_INO));
+
+        let listing = control.list(attrs.ino, None).unwrap();
+        assert_eq!(names(&listing), [".", "..", "session", "stats", "sessions"]);
+        assert_eq!(
+            names(&control.list(SESSIONS_INO, None).unwrap()),
+            [".", ".."]
+        );
+
+        assert_eq!(
+            control.lookup(attrs.ino, OsStr::new("missing"), None).err(),
+            Some(libc::ENOENT)
+        );
+        let new = control
+            .lookup(SESSION_INO, OsStr::new("new"), None)
+            .unwrap();
+        assert_eq!(new.mode & 0o777, 0o666);
+        assert_eq!(control.list(new.ino, None).err(), Some(libc::ENOTDIR));
+    }
+
+    #[test]
+    fn new_sessions_are_read_back_and_listed() {
+        let dir = tempfile::tempdir().unwrap();
+        let sessions = Mutex::new(SessionSink::new(dir.path().to_path_buf()).unwrap());
+        let stats = Mutex::new(BTreeMap::new());
+        let mut control = Control::new();
+
+        control
+            .open(NEW_SESSION_INO, 1, &stats, Some(&sessions))
+            .unwrap();
+        let pid = format!("{}\n", process::id());
+        assert_eq!(
+            control.write(1, pid.as_bytes(), Some(&sessions)),
+            Some(Ok(pid.len() as u32))
+        );
+        let session = String::from_utf8(control.read(1, 4096).unwrap().unwrap()).unwrap();
+        let session = session.trim_end();
+        assert!(session.starts_with(&format!("{}-", process::id())));
+        assert_eq!(
+            control.write(1, b"not a pid", Some(&sessions)),
+            Some(Err(libc::EINVAL))
+        );
+        assert!(control.release(1));
+
+        let listing = control.list(SESSIONS_INO, Some(&sessions)).unwrap();
+        assert_eq!(names(&listing), [".", "..", session]);
+        let directory = control
+            .lookup(SESSIONS_INO, OsStr::new(session), Some(&sessions))
+            .unwrap();
+        let events = control
+            .lookup(directory.ino, OsStr::new("events"), Some(&sessions))
+            .unwrap();
+        assert!(events.kind == FileKind::File);
+        assert_eq!(
+            control.open(directory.ino, 2, &stats, Some(&sessions)),
+            Err(libc::EISDIR)
+        );
+    }
+
+    #[test]
+    fn stats_count_every_operation() {
+        let stats = Mutex::new(BTreeMap::from([('r', 3), ('w', 1)]));
+        let mut control = Control::new();
+
+        control.open(STATS_INO, 1, &stats, None).unwrap();
+        // reads go on from where the last one stopped
+        let mut content = control.read(1, 4).unwrap().unwrap();
+        content.extend(control.read(1, 4096).unwrap().unwrap());
+        assert_eq!(
+            String::from_utf8(content).unwrap(),
+            "r 3\nw 1\nm 0\nd 0\nq 0\nt 0\nc 0\nn 0\n"
+        );
+        assert_eq!(control.read(1, 4096), Some(Ok(Vec::new())));
+        assert_eq!(control.write(1, b"0", None), Some(Err(libc::EACCES)));
+    }
+}
@@ -0,0 +1,602 @@
+use crate::control::{FIRST_REMAPPED_INO, RESERVED_INOS};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::fs as ufs;
+use std::os::unix::prelude::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use walkdir::WalkDir;
+
+// Marks a deleted entry in the upper layer, the AUFS naming is used because real overlayfs
+// whiteouts are character devices which need CAP_MKNOD to create
+const WHITEOUT_PREFIX: &str = ".wh.";
+// Marks a directory of the upper layer that hides the contents of the lower one
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+// Maps the paths of the traced tree (which always live under `root`) to the files backing them.
+//
+// Without an upper directory this is the identity. With one, the root is the read-only lower
+// layer and every modification goes to the upper layer, so the traced command never touches
+// the source tree. A file keeps the inode of the lower layer once it is copied up, the kernel
+// still knows it by that one. Files whose inodes the control directory reserves get others.
+pub struct Layers {
+    root: PathBuf,
+    upper: Option<PathBuf>,
+    // the devices of both layers, and the inodes of the lower layer by the ones of their copies
+    // in the upper one
+    root_dev: u64,
+    upper_dev: u64,
+    copied: Mutex<HashMap<u64, u64>>,
+    // the inodes given to files whose own ones are reserved or could be the ones of other files,
+    // by their device and inode
+    remapped: Mutex<HashMap<(u64, u64), u64>>,
+}
+
+impl Layers {
+    pub fn new(root: &str) -> Layers {
+        Layers {
+            root: PathBuf::from(root),
+            upper: None,
+            root_dev: 0,
+            upper_dev: 0,
+            copied: Mutex::new(HashMap::new()),
+            remapped: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_upper(root: &str, upper: PathBuf) -> io::Result<Layers> {
+        fs::create_dir_all(&upper)?;
+        let root_dev = fs::metadata(root)?.dev();
+        let upper_dev = fs::metadata(&upper)?.dev();
+
+        Ok(Layers {
+            root: PathBuf::from(root),
+            upper: Some(upper),
+            root_dev,
+            upper_dev,
+            copied: Mutex::new(HashMap::new()),
+            remapped: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // The inode of the file that `metadata` belongs to, as the traced tree shows it
+    pub fn inode(&self, metadata: &fs::Metadata) -> u64 {
+        let in_upper = self.upper.is_some() && metadata.dev() == self.upper_dev;
+        let lower = match in_upper {
+            true => self.copied.lock().unwrap().get(&metadata.ino()).copied(),
+            false => None,
+        };
+        let (dev, ino) = match lower {
+            Some(lower) => (self.root_dev, lower),
+            None => (metadata.dev(), metadata.ino()),
+        };
+
+        // files that are only in an upper layer on another filesystem than the root can have
+        // the inodes of files in the lower one
+        let upper_only = in_upper && lower.is_none() && self.upper_dev != self.root_dev;
+        if !upper_only && !RESERVED_INOS.contains(&ino) {
+            return ino;
+        }
+
+        // entries are never dropped, the inodes handed out stay unique
+        let mut remapped = self.remapped.lock().unwrap();
+        let next = FIRST_REMAPPED_INO + remapped.len() as u64;
+        *remapped.entry((dev, ino)).or_insert(next)
+    }
+
+    // Drops the inode of the lower layer that `backing` was copied up from, before it is removed
+    fn forget_copy(&self, backing: &Path) {
+        if let Ok(metadata) = backing.symlink_metadata() {
+            self.copied.lock().unwrap().remove(&metadata.ino());
+        }
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> io::Result<&'a Path> {
+        path.strip_prefix(&self.root)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+    }
+
+    // Returns the file that currently backs `path`
+    pub fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let upper = match &self.upper {
+            Some(upper) => upper,
+            None => return Ok(path.to_path_buf()),
+        };
+        let relative = self.relative(path)?;
+
+        let mut current = PathBuf::new();
+        for component in relative.components() {
+            if upper
+                .join(&current)
+                .join(whiteout_name(component.as_os_str()))
+                .exists()
+            {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
+            current.push(component);
+        }
+
+        // whiteouts and opaque markers are not entries of the tree, list_dir leaves them out too
+        let upper_path = upper.join(relative);
+        if !is_whiteout(path) && upper_path.symlink_metadata().is_ok() {
+            return Ok(upper_path);
+        }
+
+        if self.lower_exists(path)? {
+            return Ok(path.to_path_buf());
+        }
+
+        Err(io::Error::from_raw_os_error(libc::ENOENT))
+    }
+
+    fn is_upper(&self, backing: &Path) -> bool {
+        match &self.upper {
+            Some(upper) => backing.starts_with(upper),
+            None => true,
+        }
+    }
+
+    // Whether an opaque directory of the upper layer hides the contents of `dir` in the lower one
+    fn is_lower_hidden(&self, dir: &Path) -> io::Result<bool> {
+        let upper = match &self.upper {
+            Some(upper) => upper,
+            None => return Ok(false),
+        };
+
+        Ok(self
+            .relative(dir)?
+            .ancestors()
+            .any(|ancestor| upper.join(ancestor).join(OPAQUE_MARKER).exists()))
+    }
+
+    // Whether `path` exists in the lower layer and is not hidden by an opaque directory
+    fn lower_exists(&self, path: &Path) -> io::Result<bool> {
+        if path.symlink_metadata().is_err() {
+            return Ok(false);
+        }
+
+        match path.parent() {
+            Some(parent) if path != self.root => Ok(!self.is_lower_hidden(parent)?),
+            _ => Ok(true),
+        }
+    }
+
+    fn upper_path(&self, path: &Path) -> io::Result<PathBuf> {
+        match &self.upper {
+            Some(upper) => Ok(upper.join(self.relative(path)?)),
+            None => Ok(path.to_path_buf()),
+        }
+    }
+
+    // Returns the file backing `path` after copying it to the upper layer, so that it can be
+    // modified without touching the lower one
+    pub fn copy_up(&self, path: &Path) -> io::Result<PathBuf> {
+        let backing = self.resolve(path)?;
+        if self.is_upper(&backing) {
+            return Ok(backing);
+        }
+
+        if let Some(parent) = path.parent() {
+            self.copy_up(parent)?;
+        }
+
+        let target = self.upper_path(path)?;
+        let metadata = backing.symlink_metadata()?;
+        if metadata.file_type().is_symlink() {
+            ufs::symlink(fs::read_link(&backing)?, &target)?;
+        } else {
+            if metadata.is_dir() {
+                fs::create_dir(&target)?;
+                fs::set_permissions(&target, metadata.permissions())?;
+            } else {
+                fs::copy(&backing, &target)?;
+            }
+
+            // build tools compare timestamps, so the copy has to look unchanged
+            utime::set_file_times(&target, metadata.atime(), metadata.mtime())?;
+        }
+
+        let copy = target.symlink_metadata()?;
+        self.copied
+            .lock()
+            .unwrap()
+            .insert(copy.ino(), metadata.ino());
+
+        Ok(target)
+    }
+
+    // Returns where a new entry at `path` has to be created. Names of whiteouts can not be
+    // created in the upper layer, they would hide the entry they name instead.
+    pub fn prepare_create(&self, path: &Path) -> io::Result<PathBuf> {
+        if self.upper.is_some() && is_whiteout(path) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        if let Some(parent) = path.parent() {
+            self.copy_up(parent)?;
+        }
+
+        let target = self.upper_path(path)?;
+        if self.upper.is_some() {
+            remove_if_exists(&whiteout_path(&target))?;
+        }
+
+        Ok(target)
+    }
+
+    pub fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let hides_lower = self.upper.is_some() && self.lower_exists(path)?;
+        let target = self.prepare_create(path)?;
+        fs::create_dir(&target)?;
+
+        // a directory that replaces a deleted one must not show the old contents
+        if hides_lower {
+            fs::File::create(target.join(OPAQUE_MARKER))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&self, path: &Path, is_dir: bool) -> io::Result<()> {
+        let backing = self.resolve(path)?;
+        if self.upper.is_none() {
+            return if is_dir {
+                fs::remove_dir(backing)
+            } else {
+                fs::remove_file(backing)
+            };
+        }
+
+        if is_dir {
+            if !self.list_dir(path)?.is_empty() {
+                return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+            }
+            if self.is_upper(&backing) {
+                // only whiteouts and markers can be left in it
+                self.forget_copy(&backing);
+                fs::remove_dir_all(&backing)?;
+            }
+        } else if self.is_upper(&backing) {
+            self.forget_copy(&backing);
+            fs::remove_file(&backing)?;
+        }
+
+        if self.lower_exists(path)? {
+            if let Some(parent) = path.parent() {
+                self.copy_up(parent)?;
+            }
+            fs::File::create(whiteout_path(&self.upper_path(path)?))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if self.upper.is_none() {
+            return fs::rename(from, to);
+        }
+
+        // like overlayfs, directories of the lower layer are not moved, callers such as mv fall
+        // back to copying them
+        let from_lower = self.lower_exists(from)?;
+        if from_lower && self.resolve(from)?.symlink_metadata()?.is_dir() {
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+
+        let source = self.copy_up(from)?;
+        let target = self.prepare_create(to)?;
+        // the entry that is replaced goes away with its inode
+        self.forget_copy(&target);
+        fs::rename(source, target)?;
+
+        if from_lower {
+            fs::File::create(whiteout_path(&self.upper_path(from)?))?;
+        }
+
+        Ok(())
+    }
+
+    // Lists the entries of the directory at `path` together with the files backing them
+    pub fn list_dir(&self, path: &Path) -> io::Result<Vec<(OsString, PathBuf)>> {
+        let upper = match &self.upper {
+            Some(_) => self.upper_path(path)?,
+            None => {
+                return fs::read_dir(path)?
+                    .map(|entry| entry.map(|entry| (entry.file_name(), entry.path())))
+                    .collect();
+            }
+        };
+
+        let mut entries = Vec::new();
+        let mut whiteouts = Vec::new();
+        if upper.is_dir() {
+            for entry in fs::read_dir(&upper)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                match name.to_str().and_then(|n| n.strip_prefix(WHITEOUT_PREFIX)) {
+                    Some(deleted) => whiteouts.push(OsString::from(deleted)),
+                    None => entries.push((name, entry.path())),
+                }
+            }
+        }
+
+        if path.is_dir() && !self.is_lower_hidden(path)? {
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                if !whiteouts.contains(&name) && !entries.iter().any(|(n, _)| *n == name) {
+                    entries.push((name, entry.path()));
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    // Applies the changes recorded in the upper layer to the root and empties the upper layer
+    pub fn commit(&self) -> io::Result<()> {
+        let upper = match &self.upper {
+            Some(upper) => upper,
+            None => return Ok(()),
+        };
+
+        for entry in WalkDir::new(upper).min_depth(1) {
+            let entry = entry.map_err(io::Error::from)?;
+            let relative = entry.path().strip_prefix(upper).unwrap();
+            let name = entry.file_name();
+            let target = self.root.join(relative);
+
+            if name == OPAQUE_MARKER {
+                let dir = target.parent().unwrap();
+                for lower in fs::read_dir(dir)? {
+                    let lower = lower?.path();
+                    if !upper
+                        .join(relative.parent().unwrap())
+                        .join(lower.file_name().unwrap())
+                        .exists()
+                    {
+                        remove_all(&lower)?;
+                    }
+                }
+            } else if let Some(deleted) =
+                name.to_str().and_then(|n| n.strip_prefix(WHITEOUT_PREFIX))
+            {
+                remove_all(&target.with_file_name(deleted))?;
+            } else if entry.file_type().is_dir() {
+                if !target.is_dir() {
+                    remove_all(&target)?;
+                    fs::create_dir(&target)?;
+                }
+                fs::set_permissions(
+                    &target,
+                    entry.metadata().map_err(io::Error::from)?.permissions(),
+                )?;
+            } else {
+                remove_all(&target)?;
+                if entry.file_type().is_symlink() {
+                    ufs::symlink(fs::read_link(entry.path())?, &target)?;
+                } else {
+                    fs::copy(entry.path(), &target)?;
+                    let metadata = entry.metadata().map_err(io::Error::from)?;
+                    utime::set_file_times(&target, metadata.atime(), metadata.mtime())?;
+                }
+            }
+        }
+
+        fs::remove_dir_all(upper)?;
+        self.copied.lock().unwrap().clear();
+        fs::create_dir(upper)
+    }
+}
+
+// Whether `path` names a whiteout or an opaque marker, which live in the upper layer only
+fn is_whiteout(path: &Path) -> bool {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .is_some_and(|name| name.starts_with(WHITEOUT_PREFIX))
+}
+
+fn whiteout_name(name: &OsStr) -> OsString {
+    let mut whiteout = OsString::from(WHITEOUT_PREFIX);
+    whiteout.push(name);
+    whiteout
+}
+
+pub fn whiteout_path(path: &Path) -> PathBuf {
+    path.with_file_name(whiteout_name(path.file_name().unwrap_or_default()))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn remove_all(path: &Path) -> io::Result<()> {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Layers, OPAQUE_MARKER};
+    use crate::control::FIRST_REMAPPED_INO;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    fn names(layers: &Layers, dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = layers
+            .list_dir(dir)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name.into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn changes_go_to_the_upper_layer() {
+        let root = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("src")).unwrap();
+        fs::write(root.path().join("src/a.c"), "lower").unwrap();
+        fs::write(root.path().join("src/b.c"), "lower").unwrap();
+
+        let layers =
+            Layers::with_upper(root.path().to_str().unwrap(), upper.path().to_path_buf()).unwrap();
+        let src = root.path().join("src");
+
+        fs::write(layers.copy_up(&src.join("a.c")).unwrap(), "upper").unwrap();
+        layers.remove(&src.join("b.c"), false).unwrap();
+        fs::write(layers.prepare_create(&src.join("c.c")).unwrap(), "new").unwrap();
+
+        assert_eq!(fs::read_to_string(src.join("a.c")).unwrap(), "lower");
+        assert!(src.join("b.c").exists());
+        assert!(!src.join("c.c").exists());
+
+        let a = layers.resolve(&src.join("a.c")).unwrap();
+        assert_eq!(fs::read_to_string(a).unwrap(), "upper");
+        assert!(layers.resolve(&src.join("b.c")).is_err());
+        assert_eq!(names(&layers, &src), ["a.c", "c.c"]);
+    }
+
+    #[test]
+    fn recreated_directories_hide_the_lower_contents() {
+        let root = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("out")).unwrap();
+        fs::write(root.path().join("out/old.o"), "").unwrap();
+
+        let layers =
+            Layers::with_upper(root.path().to_str().unwrap(), upper.path().to_path_buf()).unwrap();
+        let out = root.path().join("out");
+
+        assert!(layers.remove(&out, true).is_err());
+        layers.remove(&out.join("old.o"), false).unwrap();
+        layers.remove(&out, true).unwrap();
+        assert!(layers.resolve(&out).is_err());
+
+        layers.create_dir(&out).unwrap();
+        fs::write(layers.prepare_create(&out.join("new.o")).unwrap(), "").unwrap();
+        assert_eq!(names(&layers, &out), ["new.o"]);
+    }
+
+    #[test]
+    fn commit_applies_the_upper_layer() {
+        let root = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("kept"), "lower").unwrap();
+        fs::write(root.path().join("deleted"), "lower").unwrap();
+        fs::write(root.path().join("from"), "moved").unwrap();
+
+        let layers =
+            Layers::with_upper(root.path().to_str().unwrap(), upper.path().to_path_buf()).unwrap();
+
+        fs::write(layers.copy_up(&root.path().join("kept")).unwrap(), "upper").unwrap();
+        layers.remove(&root.path().join("deleted"), false).unwrap();
+        layers
+            .rename(&root.path().join("from"), &root.path().join("to"))
+            .unwrap();
+        layers.commit().unwrap();
+
+        assert_eq!(
+            fs::read_to_string(root.path().join("kept")).unwrap(),
+            "upper"
+        );
+        assert_eq!(fs::read_to_string(root.path().join("to")).unwrap(), "moved");
+        assert!(!root.path().join("deleted").exists());
+        assert!(!root.path().join("from").exists());
+        assert_eq!(fs::read_dir(upper.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn copied_up_files_keep_their_inode() {
+        let root = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("a.c"), "lower").unwrap();
+
+        let layers =
+            Layers::with_upper(root.path().to_str().unwrap(), upper.path().to_path_buf()).unwrap();
+        let a = root.path().join("a.c");
+        let lower = fs::metadata(&a).unwrap().ino();
+
+        let copy = fs::metadata(layers.copy_up(&a).unwrap()).unwrap();
+        assert_ne!(copy.ino(), lower);
+        assert_eq!(layers.inode(&copy), lower);
+    }
+
+    #[test]
+    fn upper_files_on_another_filesystem_get_inodes_of_their_own() {
+        let root = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("a.c"), "lower").unwrap();
+
+        let mut layers =
+            Layers::with_upper(root.path().to_str().unwrap(), upper.path().to_path_buf()).unwrap();
+        let created = layers.prepare_create(&root.path().join("a.o")).unwrap();
+        fs::write(&created, "").unwrap();
+        let created = fs::metadata(created).unwrap();
+        // on the same filesystem the inodes can not be the ones of lower files
+        assert_eq!(layers.inode(&created), created.ino());
+
+        layers.root_dev = layers.upper_dev + 1;
+        let ino = layers.inode(&created);
+        assert!(ino >= FIRST_REMAPPED_INO);
+        assert_eq!(layers.inode(&created), ino);
+
+        let a = root.path().join("a.c");
+        let lower = fs::metadata(&a).unwrap().ino();
+        let copy = fs::metadata(layers.copy_up(&a).unwrap()).unwrap();
+        assert_eq!(layers.inode(&copy), lower);
+    }
+
+    #[test]
+    fn whiteouts_are_not_found() {
+        let root = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("foo"), "lower").unwrap();
+        fs::write(root.path().join(".wh.bar"), "lower").unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+
+        let layers =
+            Layers::with_upper(root.path().to_str().unwrap(), upper.path().to_path_buf()).unwrap();
+        layers.remove(&root.path().join("foo"), false).unwrap();
+        let dir = layers.copy_up(&root.path().join("dir")).unwrap();
+        fs::write(dir.join(OPAQUE_MARKER), "").unwrap();
+
+        for name in [".wh.foo", "dir/.wh..wh..opq"] {
+            let error = layers.resolve(&root.path().join(name)).unwrap_err();
+            assert_eq!(error.raw_os_error(), Some(libc::ENOENT));
+        }
+        // a file of the lower layer is no whiteout, whatever its name
+        assert_eq!(
+            layers.resolve(&root.path().join(".wh.bar")).unwrap(),
+            root.path().join(".wh.bar")
+        );
+    }
+
+    #[test]
+    fn whiteout_names_can_not_be_created() {
+        let root = tempfile::tempdir().unwrap();
+        let upper = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("foo"), "lower").unwrap();
+
+        let layers =
+            Layers::with_upper(root.path().to_str().unwrap(), upper.path().to_path_buf()).unwrap();
+
+        let error = layers
+            .prepare_create(&root.path().join(".wh.foo"))
+            .unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(names(&layers, root.path()), ["foo"]);
+    }
+}
@@ -0,0 +1,94 @@
+use log::{debug, warn};
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+const READY_MESSAGE: &str = "READY=1";
+
+// Where to report that the filesystem is mounted and serving requests
+enum Target {
+    // a file descriptor inherited from the parent, usually the write end of a pipe or fifo
+    Fd(File),
+    // a unix datagram socket in the style of sd_notify(3)
+    Socket(String),
+}
+
+pub struct Readiness {
+    targets: Vec<Target>,
+}
+
+impl Readiness {
+    // Collects the readiness targets, the given file descriptor and the socket in $NOTIFY_SOCKET.
+    // Fails if the file descriptor is not open, the parent would wait for the message forever.
+    pub fn new(ready_fd: Option<RawFd>) -> io::Result<Readiness> {
+        let mut targets = Vec::new();
+
+        if let Some(fd) = ready_fd {
+            if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                let e = io::Error::last_os_error();
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("ready fd {} can not be used: {}", fd, e),
+                ));
+            }
+            // the descriptor is inherited and owned by nobody else in this process
+            targets.push(Target::Fd(unsafe { File::from_raw_fd(fd) }));
+        }
+
+        if let Ok(socket) = env::var("NOTIFY_SOCKET") {
+            targets.push(Target::Socket(socket));
+        }
+
+        Ok(Readiness { targets })
+    }
+
+    // Reports readiness to every target. File descriptors are closed afterwards, so a reader
+    // sees either the message or EOF if the process died before getting here.
+    pub fn notify(self) {
+        for target in self.targets {
+            let result = match target {
+                Target::Fd(mut file) => writeln!(file, "{}", READY_MESSAGE),
+                Target::Socket(socket) => notify_socket(&socket),
+            };
+
+            match result {
+                Ok(_) => debug!("Reported readiness"),
+                Err(e) => warn!("Failed to report readiness: {}", e),
+            }
+        }
+    }
+}
+
+fn notify_socket(socket: &str) -> io::Result<()> {
+    let addr = match socket.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+
+    UnixDatagram::unbound()?.send_to_addr(READY_MESSAGE.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Readiness;
+    use std::io::Read;
+    use std::os::fd::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn the_ready_fd_has_to_be_open() {
+        // far above the limit of open files
+        let e = Readiness::new(Some(1 << 24)).err().unwrap();
+        assert!(e.to_string().contains(&(1 << 24).to_string()));
+
+        let (mut reader, writer) = UnixStream::pair().unwrap();
+        Readiness::new(Some(writer.into_raw_fd())).unwrap().notify();
+        let mut message = String::new();
+        reader.read_to_string(&mut message).unwrap();
+        assert_eq!(message, "READY=1\n");
+    }
+}
@@ -0,0 +1,302 @@
+use crate::sink::{TraceEvent, TraceSink};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::prelude::MetadataExt;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// the variable that binds a process and everything it starts to a session
+pub const SESSION_VARIABLE: &str = "CAIRN_SESSION";
+// the prefix of the cgroups that bind every process in them to the session named after it
+pub const CGROUP_PREFIX: &str = "cairn-";
+// bindings of processes that are long gone are dropped once there are this many of them
+const MAX_BINDINGS: usize = 65536;
+
+// Gives every traced session an event stream of its own, next to the trace of everything.
+//
+// A session is registered by creating `<id>.log` in the session directory, or by starting it for
+// a process through `start`, and it is stopped by removing that file again. Processes started
+// in a cgroup named `cairn-<id>`, or below one, belong to the session whatever started them, so
+// daemons and processes that were reparented to init are not lost. Otherwise processes started
+// with CAIRN_SESSION=<id> in their environment belong to the session, and so does everything
+// they start, unless it is started with another session. Processes are told apart by their
+// start time as well as their pid, so a reused pid is not mistaken for the process that had it
+// before.
+pub struct SessionSink {
+    dir: PathBuf,
+    bindings: HashMap<u32, Binding>,
+    // the processes that sessions were started for, which are kept when the bindings are dropped
+    started: HashMap<u32, Binding>,
+    streams: HashMap<String, File>,
+}
+
+#[derive(Clone)]
+struct Binding {
+    start_time: u64,
+    session: Option<String>,
+}
+
+impl SessionSink {
+    pub fn new(dir: PathBuf) -> io::Result<SessionSink> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(SessionSink {
+            dir,
+            bindings: HashMap::new(),
+            started: HashMap::new(),
+            streams: HashMap::new(),
+        })
+    }
+
+    // Registers a new session for `pid` and everything it starts from now on, returns its id
+    pub fn start(&mut self, pid: u32) -> io::Result<String> {
+        let (_, start_time) =
+            process_stat(pid).ok_or_else(|| io::Error::from_raw_os_error(libc::ESRCH))?;
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let session = format!("{}-{}", pid, since_epoch.as_nanos());
+
+        File::create(self.stream_path(&session))?;
+        let binding = Binding {
+            start_time,
+            session: Some(session.clone()),
+        };
+        self.bindings.insert(pid, binding.clone());
+        self.started.insert(pid, binding);
+
+        Ok(session)
+    }
+
+    // The ids of the registered sessions
+    pub fn sessions(&self) -> io::Result<Vec<String>> {
+        let mut sessions = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            if let Some(session) = name.to_str().and_then(|n| n.strip_suffix(".log")) {
+                sessions.push(session.to_string());
+            }
+        }
+        sessions.sort();
+
+        Ok(sessions)
+    }
+
+    // Opens the events of `session` for reading, they are in the format of the trace file
+    pub fn events(&self, session: &str) -> io::Result<File> {
+        File::open(self.stream_path(session))
+    }
+
+    // The session that the process `pid` with the parent `ppid` belongs to, if it is registered
+    pub fn registered_session(&mut self, pid: u32, ppid: i32) -> Option<String> {
+        self.session_of(pid, ppid)
+            .filter(|session| self.stream_path(session).exists())
+    }
+
+    fn stream_path(&self, session: &str) -> PathBuf {
+        self.dir.join(format!("{}.log", session))
+    }
+
+    fn session_of(&mut self, pid: u32, ppid: i32) -> Option<String> {
+        let (parent, start_time) = match process_stat(pid) {
+            Some(stat) => stat,
+            // the process is gone already, the best guess is what it was bound to before
+            None => {
+                return match self.bindings.get(&pid) {
+                    Some(binding) => binding.session.clone(),
+                    None if ppid > 1 => self.session_of(ppid as u32, -1),
+                    None => None,
+                }
+            }
+        };
+
+        for bindings in [&self.bindings, &self.started] {
+            if let Some(binding) = bindings.get(&pid) {
+                if binding.start_time == start_time {
+                    return binding.session.clone();
+                }
+            }
+        }
+
+        let session = match cgroup_session(pid).or_else(|| environment_session(pid)) {
+            Some(session) => Some(session),
+            None if parent > 1 => self.session_of(parent as u32, -1),
+            None => None,
+        };
+
+        if self.bindings.len() >= MAX_BINDINGS {
+            self.bindings.clear();
+        }
+        self.bindings.insert(
+            pid,
+            Binding {
+                start_time,
+                session: session.clone(),
+            },
+        );
+
+        session
+    }
+
+    // Returns the stream of `session`, if it is registered
+    fn stream(&mut self, session: &str) -> io::Result<Option<&mut File>> {
+        // the file is removed once the session is over
+        let stopped = match self.streams.get(session) {
+            Some(file) => file.metadata()?.nlink() == 0,
+            None => true,
+        };
+
+        if stopped {
+            self.streams.remove(session);
+            match OpenOptions::new()
+                .append(true)
+                .open(self.stream_path(session))
+            {
+                Ok(file) => {
+                    self.streams.insert(session.to_string(), file);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(self.streams.get_mut(session))
+    }
+}
+
+impl TraceSink for SessionSink {
+    fn record(&mut self, event: TraceEvent) -> io::Result<()> {
+        if let Some(session) = self.session_of(event.tgid, event.ppid) {
+            if let Some(stream) = self.stream(&session)? {
+                // a single write, so that a reader never sees half of the line
+                stream.write_all(format!("{}\n", event).as_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Reads the parent and the start time of `pid` from `/proc/<pid>/stat`
+fn process_stat(pid: u32) -> Option<(i32, u64)> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the name of the command is in parentheses and can contain anything, even spaces
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+
+    // the state is the third field of the file, the parent the fourth and the start time the
+    // twenty-second
+    let ppid = fields.get(1)?.parse().ok()?;
+    let start_time = fields.get(19)?.parse().ok()?;
+    Some((ppid, start_time))
+}
+
+// The session of the cgroup that `pid` is in, if it is in one of a session
+fn cgroup_session(pid: u32) -> Option<String> {
+    let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    session_in_cgroups(&cgroups).map(String::from)
+}
+
+// Finds the session in the contents of `/proc/<pid>/cgroup`. Only the hierarchy of cgroup v2 is
+// looked at, which has the id 0, and the innermost cgroup of a session wins.
+fn session_in_cgroups(cgroups: &str) -> Option<&str> {
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    path.rsplit('/')
+        .filter_map(|name| name.strip_prefix(CGROUP_PREFIX))
+        .find(|session| valid_session(session))
+}
+
+// The session in the environment `pid` was started with
+fn environment_session(pid: u32) -> Option<String> {
+    let environment = fs::read(format!("/proc/{}/environ", pid)).ok()?;
+    let prefix = format!("{}=", SESSION_VARIABLE);
+
+    environment
+        .split(|byte| *byte == 0)
+        .find_map(|variable| variable.strip_prefix(prefix.as_bytes()))
+        .and_then(|session| std::str::from_utf8(session).ok())
+        .filter(|session| valid_session(session))
+        .map(String::from)
+}
+
+// Names that could point outside of the session directory are ignored
+fn valid_session(session: &str) -> bool {
+    !session.is_empty()
+        && session
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{session_in_cgroups, SessionSink, SESSION_VARIABLE};
+    use crate::sink::TraceEvent;
+    use crate::TraceSink;
+    use std::process::Command;
+    use std::time::Duration;
+    use std::{fs, thread};
+
+    #[test]
+    fn events_of_a_session_go_to_its_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = SessionSink::new(dir.path().to_path_buf()).unwrap();
+        fs::write(dir.path().join("build-1.log"), "").unwrap();
+
+        // a shell in the session that starts a child outside of it, which still belongs to it
+        let mut child = Command::new("sh")
+            .args(["-c", "env -u CAIRN_SESSION sleep 5; true"])
+            .env(SESSION_VARIABLE, "build-1")
+            .spawn()
+            .unwrap();
+        // the child only leaves the session once `env` has started `sleep`
+        let children = format!("/proc/{}/task/{}/children", child.id(), child.id());
+        let mut sleep = None;
+        for _ in 0..500 {
+            sleep = fs::read_to_string(&children)
+                .unwrap_or_default()
+                .split_whitespace()
+                .next()
+                .and_then(|pid| pid.parse::<u32>().ok())
+                .filter(|pid| {
+                    fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap_or_default()
+                        == "sleep\n"
+                });
+            if sleep.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let sleep = sleep.unwrap();
+
+        let event = |tgid: u32, path: &str| TraceEvent {
+            time: 0,
+            tgid,
+            tid: tgid,
+            ppid: -1,
+            uid: 0,
+            gid: 0,
+            op: 'w',
+            paths: vec![path.to_string()],
+        };
+        sink.record(event(sleep, "/out.o")).unwrap();
+        sink.record(event(std::process::id(), "/unrelated"))
+            .unwrap();
+        Command::new("kill")
+            .arg(sleep.to_string())
+            .status()
+            .unwrap();
+        child.wait().unwrap();
+
+        let stream = fs::read_to_string(dir.path().join("build-1.log")).unwrap();
+        assert_eq!(stream, format!("0: {}|{}|-1|0|0|w|/out.o\n", sleep, sleep));
+    }
+
+    #[test]
+    fn sessions_are_found_in_cgroups() {
+        let cgroups = "12:pids:/user.slice\n0::/user.slice/cairn-build-1/make\n";
+        assert_eq!(session_in_cgroups(cgroups), Some("build-1"));
+        // only cgroup v2 binds processes to sessions
+        assert_eq!(session_in_cgroups("4:memory:/cairn-build-1\n0::/\n"), None);
+        assert_eq!(session_in_cgroups("0::/cairn-../x\n"), None);
+    }
+}
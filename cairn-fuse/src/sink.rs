@@ -0,0 +1,185 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+// A single operation performed by a traced process
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEvent {
+    // seconds since the epoch
+    pub time: i64,
+    pub tgid: u32,
+    pub tid: u32,
+    // -1 if the parent could not be resolved
+    pub ppid: i32,
+    pub uid: u32,
+    pub gid: u32,
+    pub op: char,
+    pub paths: Vec<String>,
+}
+
+// The line format of the trace file, which is what cairn-cli parses
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}|{}|{}|{}|{}|{}|{}",
+            self.time,
+            self.tgid,
+            self.tid,
+            self.ppid,
+            self.uid,
+            self.gid,
+            self.op,
+            self.paths.join("|")
+        )
+    }
+}
+
+// Destination of the trace events
+pub trait TraceSink: Send {
+    fn record(&mut self, event: TraceEvent) -> io::Result<()>;
+
+    // Called when the filesystem is unmounted
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Writes every event as a line of a file
+pub struct FileSink {
+    writer: LineWriter<File>,
+}
+
+impl FileSink {
+    pub fn new(file: File) -> FileSink {
+        FileSink {
+            writer: LineWriter::new(file),
+        }
+    }
+
+    // Appends to the file at `path`, creating it and its parent directories if needed
+    pub fn create(path: &Path) -> io::Result<FileSink> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileSink::new(file))
+    }
+}
+
+impl TraceSink for FileSink {
+    fn record(&mut self, event: TraceEvent) -> io::Result<()> {
+        writeln!(self.writer, "{}", event)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Sends every event to the receiving end of a channel
+pub struct ChannelSink {
+    sender: Sender<TraceEvent>,
+}
+
+impl ChannelSink {
+    pub fn new(sender: Sender<TraceEvent>) -> ChannelSink {
+        ChannelSink { sender }
+    }
+}
+
+impl TraceSink for ChannelSink {
+    fn record(&mut self, event: TraceEvent) -> io::Result<()> {
+        self.sender
+            .send(event)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+// Keeps the last `capacity` events in memory. Clones share the buffer, so one of them can be
+// handed to the tracer while another one is kept around to read the events.
+#[derive(Clone)]
+pub struct RingBuffer {
+    capacity: usize,
+    events: Arc<Mutex<VecDeque<TraceEvent>>>,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> RingBuffer {
+        RingBuffer {
+            capacity,
+            events: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    // Returns the buffered events, oldest first
+    pub fn events(&self) -> Vec<TraceEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+
+    // Returns the buffered events, oldest first, and empties the buffer
+    pub fn drain(&self) -> Vec<TraceEvent> {
+        self.events.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl TraceSink for RingBuffer {
+    fn record(&mut self, event: TraceEvent) -> io::Result<()> {
+        let mut events = self.events.lock().unwrap();
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RingBuffer, TraceEvent, TraceSink};
+
+    fn event(op: char, path: &str) -> TraceEvent {
+        TraceEvent {
+            time: 1700000000,
+            tgid: 10,
+            tid: 11,
+            ppid: 1,
+            uid: 0,
+            gid: 0,
+            op,
+            paths: vec![path.to_string()],
+        }
+    }
+
+    #[test]
+    fn events_are_formatted_as_trace_lines() {
+        let mut rename = event('m', "/root/a");
+        rename.paths.push("/root/b".to_string());
+
+        assert_eq!(
+            rename.to_string(),
+            "1700000000: 10|11|1|0|0|m|/root/a|/root/b"
+        );
+    }
+
+    #[test]
+    fn ring_buffer_keeps_the_latest_events() {
+        let buffer = RingBuffer::new(2);
+        let mut sink = buffer.clone();
+        for path in ["a", "b", "c"] {
+            sink.record(event('r', path)).unwrap();
+        }
+
+        assert_eq!(buffer.events(), [event('r', "b"), event('r', "c")]);
+        assert_eq!(buffer.drain().len(), 2);
+        assert!(buffer.events().is_empty());
+    }
+}
@@ -1,16 +1,22 @@
 #!/bin/bash
 
 usage() {
-  echo "Usage: $0 [-h] <chroot_dir> <workdir> <command>" 1>&2
+  echo "Usage: $0 [-h] [-p <pid_file>] <chroot_dir> <workdir> <command> [args...]" 1>&2
   echo "  -h: Display this help message" 1>&2
+  echo "  -p: Write the pid of the command to <pid_file>" 1>&2
   exit 1
 }
 
-while getopts ":h" opt; do
+pid_file=""
+
+while getopts ":hp:" opt; do
   case ${opt} in
   h)
     usage
     ;;
+  p)
+    pid_file=$OPTARG
+    ;;
   \?)
     echo "Invalid option: -$OPTARG" 1>&2
     usage
@@ -37,16 +43,46 @@ fi
 
 chroot_dir=$1
 workdir=$2
-command=$3
+shift 2
 
 if [ ! -d "$chroot_dir" ]; then
   echo "Directory $chroot_dir does not exist" 1>&2
   exit 1
 fi
 
-(chroot "${chroot_dir}" /bin/bash -c "cd ${workdir} && ${command}" ) &
+# in a session, the command gets a cgroup of cgroup v2 named after it, through which cairn-fuse
+# finds everything the command starts, even what leaves its process tree. Without cgroup v2 or
+# the permission to use it, the session only follows the processes through CAIRN_SESSION.
+cgroup=""
+if [ -n "${CAIRN_SESSION}" ] && [ -f /sys/fs/cgroup/cgroup.procs ]; then
+  own_cgroup=$(sed -n 's/^0:://p' /proc/self/cgroup)
+  if [ -n "$own_cgroup" ] && mkdir "/sys/fs/cgroup${own_cgroup%/}/cairn-${CAIRN_SESSION}" 2>/dev/null; then
+    cgroup="/sys/fs/cgroup${own_cgroup%/}/cairn-${CAIRN_SESSION}"
+  fi
+fi
+
+# the arguments are passed on to the script positionally, so none of them is interpreted by a shell
+(
+  if [ -n "$cgroup" ]; then
+    echo "$BASHPID" > "${cgroup}/cgroup.procs"
+  fi
+  exec chroot "${chroot_dir}" /bin/bash -c 'cd "$1" && shift && exec "$@"' bash "${workdir}" "$@"
+) &
 
 pid=$!
 
+# the pid goes to a file of its own, the output of the command is left alone
+if [ -n "$pid_file" ]; then
+  echo "$pid" > "$pid_file"
+fi
+
+# the status of wait, and so of this script, is the one of the command
 wait "$pid"
-echo "$pid"
\ No newline at end of file
+status=$?
+
+# processes of the command that are still running keep the cgroup
+if [ -n "$cgroup" ]; then
+  rmdir "$cgroup" 2>/dev/null
+fi
+
+exit "$status"
@@ -1,4 +1,3 @@
 #!/bin/bash 
 
 rm -rf bin dev etc lib* proc sys usr 
-rm tracer.log 2> /dev/null
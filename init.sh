@@ -33,6 +33,10 @@ if [ -z "$MNT_DIR" ]; then
     echo "MNT_DIR is not set in the env file."
     exit 1
 fi
+if [ -z "$LOG_DIR" ]; then
+    echo "LOG_DIR is not set in the env file."
+    exit 1
+fi
 
 if ! docker info >/dev/null 2>&1; then
 	echo "Docker is not running. Quitting."
@@ -58,6 +62,7 @@ docker run \
   --detach \
 	--privileged \
 	-v "$(pwd)/$MNT_DIR":/usr/src/dockermount \
+	-v "$(pwd)/$LOG_DIR":/usr/src/cairnlog \
 	--cap-add SYS_ADMIN \
 	--name "build-env" \
 	-it "build-env:test" 
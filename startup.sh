@@ -1,21 +1,46 @@
 #!/bin/bash
 
- start the tracer
-cairn-fuse /usr/src/dockermount /usr/src/fusemount > app.log 2>&1 &
+# start the tracer, it reports on fd 3 once the filesystem is mounted
+ready=$(mktemp -u)
+mkfifo "$ready"
+cairn-fuse --allow-other --ready-fd 3 --trace-file /usr/src/cairnlog/tracer.log --backup-dir /usr/src/cairnlog/backup --session-dir /usr/src/cairnlog/sessions /usr/src/dockermount /usr/src/fusemount > app.log 2>&1 3>"$ready" &
 
-echo "$!"
+tracer=$!
+echo "$tracer"
 
-# wait for the fs to start
-while [ ! -f ./.cairn-fuse-ready ]; do
-    sleep 1
-done
+bind_dirs="proc sys dev bin etc lib lib32 lib64 libx32 usr/lib usr/lib32 usr/lib64 usr/libx32 usr/include"
+
+cleanup() {
+    # the bind mounts keep the fuse mount busy, so they have to go first
+    for f in $bind_dirs; do
+        umount --lazy /usr/src/fusemount/$f 2> /dev/null
+    done
+
+    # cairn-fuse flushes the trace and unmounts itself on SIGTERM
+    kill -TERM "$tracer" 2> /dev/null
+    wait "$tracer"
+    rm -f "$ready"
+}
+
+trap cleanup EXIT
+trap 'exit 129' HUP
+trap 'exit 130' INT
+trap 'exit 143' TERM
+
+# wait for the fs to start, EOF means that the tracer exited before mounting
+if ! read -r status < "$ready" || [ "$status" != "READY=1" ]; then
+    echo "cairn-fuse failed to start, see app.log" 1>&2
+    exit 1
+fi
+rm -f "$ready"
 
 # mount relevant dirs
-for f in proc sys dev bin etc lib lib32 lib64 libx32 usr/lib usr/lib32 usr/lib64 usr/libx32 usr/include; do
+for f in $bind_dirs; do
     mkdir -p /usr/src/fusemount/$f
     mount --bind /$f /usr/src/fusemount/$f
 done
 
-/bin/bash
-
-# TODO: handle SIGTERM, SIGKILL, SIGINT to gracefully unmount
+# keep the container running, unlike a foreground command `wait` returns as soon as a trapped
+# signal arrives so that the mounts are cleaned up before docker gives up and sends SIGKILL
+sleep infinity &
+wait "$!"
@@ -6,3 +6,6 @@ docker container kill build-env-bench
 cd host_mnt
 ./clean.sh
 cd ..
+
+rm -f host_log/tracer.log
+rm -rf host_log/backup host_log/snapshot host_log/sessions